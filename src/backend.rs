@@ -0,0 +1,106 @@
+//! The operations SnowPlow runs through nix, behind a trait so that they can be
+//! replaced, for instance by the fake backend used to test the command line.
+
+use std::{
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{nix::NixVersion, signal, Error, Verification};
+
+/// What a nix command printed, and wether it succeeded.
+pub struct Output {
+    pub success: bool,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// How often a wait checks wether it must be stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// What a nix command reports to while it runs, and when it must be stopped.
+pub struct Control<'a> {
+    /// Given every line nix prints on its standard error, as soon as it is printed.
+    pub progress: &'a mut dyn FnMut(&str),
+    /// When the operation this command is part of started.
+    pub start: Instant,
+    /// How long the operation may last before nix is killed.
+    pub timeout: Option<Duration>,
+}
+
+impl<'a> Control<'a> {
+    /// Control a command which may run as long as it needs.
+    pub fn new(progress: &'a mut dyn FnMut(&str)) -> Self {
+        Control {
+            progress,
+            start: Instant::now(),
+            timeout: None,
+        }
+    }
+
+    /// Return why nix must be stopped, if it must.
+    pub fn stopped(&self) -> Option<Error> {
+        if signal::interrupted() {
+            return Some(Error::Interrupted);
+        }
+        let timeout = self.timeout?;
+        (self.start.elapsed() >= timeout).then_some(Error::Timeout(timeout))
+    }
+
+    /// Wait for the given duration, unless the operation must be stopped before.
+    pub fn wait(&self, duration: Duration) -> Result<(), Vec<Error>> {
+        let end = Instant::now() + duration;
+        loop {
+            if let Some(reason) = self.stopped() {
+                return Err(vec![reason]);
+            }
+            let now = Instant::now();
+            if now >= end {
+                return Ok(());
+            }
+            thread::sleep(POLL_INTERVAL.min(end - now));
+        }
+    }
+}
+
+/// A way to run nix.
+///
+/// Each operation returns an error only if nix could not be run at all, or was
+/// stopped by the [`Control`]: the failures reported by nix itself are in the returned [`Output`].
+pub trait Backend: Send + Sync {
+    /// Return the version of nix, failing if it is not supported.
+    fn version(&self) -> Result<NixVersion, Vec<Error>>;
+
+    /// Show the outputs of the flake at `path`, which fails if it is not a valid flake.
+    fn check(&self, path: &Path, control: &mut Control) -> Result<Output, Vec<Error>>;
+
+    /// Print the metadata of the flake at `path` as JSON.
+    fn metadata(&self, path: &Path, control: &mut Control)
+        -> Result<Output, Vec<Error>>;
+
+    /// Lock the inputs of the flake at `path` which are not locked yet, without updating the others.
+    fn lock(
+        &self,
+        path: &Path,
+        args: &[String],
+        control: &mut Control,
+    ) -> Result<Output, Vec<Error>>;
+
+    /// Update the given inputs of the flake at `path`, or every input if none is given.
+    fn update(
+        &self,
+        path: &Path,
+        inputs: &[String],
+        args: &[String],
+        control: &mut Control,
+    ) -> Result<Output, Vec<Error>>;
+
+    /// Run a verification step on the flake at `path`.
+    fn verify(
+        &self,
+        path: &Path,
+        step: &Verification,
+        control: &mut Control,
+    ) -> Result<Output, Vec<Error>>;
+}
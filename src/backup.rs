@@ -0,0 +1,222 @@
+//! Backups of the lock files, taken before each update so that they can be rolled back.
+//!
+//! Each run of `snow-plow update` has its own directory, named after the time
+//! it started, holding a copy of the lock file of every flake the run changed.
+//! A flake which had no lock file before the update has an empty marker instead.
+//! The files are named after the flakes, percent-encoded so that any name stays
+//! inside the directory of its run.
+
+use std::{
+    fs::{self, DirBuilder},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use chrono::Local;
+
+use crate::{lock::LOCK_FILE, Error};
+
+/// The name of the backup directory, inside the state directory.
+pub const BACKUP_DIR: &str = "backups";
+
+/// The extension of the backup of a lock file.
+const LOCK_EXTENSION: &str = "lock";
+/// The extension of the marker of a flake which had no lock file.
+const ABSENT_EXTENSION: &str = "absent";
+
+/// The backups of every run.
+pub struct Backups {
+    dir: PathBuf,
+}
+
+impl Backups {
+    pub fn new(state_dir: &Path) -> Self {
+        Backups {
+            dir: state_dir.join(BACKUP_DIR),
+        }
+    }
+
+    /// Return a new identifier for a run, based on the current time, and create its directory.
+    /// The directory is created atomically, so that concurrent runs never share an identifier,
+    /// and must be kept until the run has been journaled. The time is precise to the
+    /// microsecond, since the directories of runs without backups are removed afterwards.
+    pub fn new_run(&self) -> Result<String, Vec<Error>> {
+        DirBuilder::new()
+            .recursive(true)
+            .create(&self.dir)
+            .map_err(|e| vec![Error::Io(e, self.dir.display().to_string())])?;
+        let base = Local::now().format("%Y%m%d-%H%M%S%.6f").to_string();
+        let mut run = base.clone();
+        let mut i = 1;
+        loop {
+            let run_dir = self.dir.join(&run);
+            match fs::create_dir(&run_dir) {
+                Ok(()) => return Ok(run),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    i += 1;
+                    run = format!("{}-{}", base, i);
+                }
+                Err(e) => return Err(vec![Error::Io(e, run_dir.display().to_string())]),
+            }
+        }
+    }
+
+    /// Save the lock file of a flake before it is updated, or record that it has none.
+    pub fn save(&self, run: &str, name: &str, lock: Option<&[u8]>) -> Result<(), Vec<Error>> {
+        let (path, content) = match lock {
+            Some(lock) => (self.file(run, name, LOCK_EXTENSION), lock),
+            None => (self.file(run, name, ABSENT_EXTENSION), &[][..]),
+        };
+        fs::write(&path, content).map_err(|e| vec![Error::Io(e, path.display().to_string())])
+    }
+
+    /// Remove the backup of a flake the run did not change.
+    pub fn discard(&self, run: &str, name: &str) -> Result<(), Vec<Error>> {
+        for extension in [LOCK_EXTENSION, ABSENT_EXTENSION] {
+            let path = self.file(run, name, extension);
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    return Err(vec![Error::Io(e, path.display().to_string())]);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the directory of a run if it holds no backup.
+    pub fn close_run(&self, run: &str) {
+        // This fails if the directory is not empty, which is expected.
+        let _ = fs::remove_dir(self.dir.join(run));
+    }
+
+    /// Return the identifiers of every run with backups, from the oldest to the most recent.
+    pub fn runs(&self) -> Result<Vec<String>, Vec<Error>> {
+        let mut runs = Self::entries(&self.dir)?;
+        runs.sort();
+        Ok(runs)
+    }
+
+    /// Return the names of the flakes with a backup in the given run.
+    pub fn flakes(&self, run: &str) -> Result<Vec<String>, Vec<Error>> {
+        let mut flakes: Vec<String> = Self::entries(&self.dir.join(run))?
+            .into_iter()
+            .filter_map(|entry| {
+                let path = Path::new(&entry);
+                let extension = path.extension()?;
+                (extension == LOCK_EXTENSION || extension == ABSENT_EXTENSION)
+                    .then(|| path.file_stem())
+                    .flatten()
+                    .map(|stem| decode(&stem.to_string_lossy()))
+            })
+            .collect();
+        flakes.sort();
+        Ok(flakes)
+    }
+
+    /// Restore the lock file of a flake, as it was before the given run.
+    /// Return `false` if there is no backup of the flake in this run.
+    pub fn restore(&self, run: &str, name: &str, flake_dir: &Path) -> Result<bool, Vec<Error>> {
+        let lock_path = flake_dir.join(LOCK_FILE);
+        let backup_path = self.file(run, name, LOCK_EXTENSION);
+
+        if backup_path.exists() {
+            let tmp_path = lock_path.with_extension("tmp");
+            fs::copy(&backup_path, &tmp_path)
+                .and_then(|_| fs::rename(&tmp_path, &lock_path))
+                .map_err(|e| vec![Error::Io(e, lock_path.display().to_string())])?;
+            Ok(true)
+        } else if self.file(run, name, ABSENT_EXTENSION).exists() {
+            match fs::remove_file(&lock_path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    Err(vec![Error::Io(e, lock_path.display().to_string())])
+                }
+                _ => Ok(true),
+            }
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Move the backups of a flake to its new name, so that it can still be rolled back.
+    pub fn rename(&self, old_name: &str, new_name: &str) -> Result<(), Vec<Error>> {
+        for run in self.runs()? {
+            for extension in [LOCK_EXTENSION, ABSENT_EXTENSION] {
+                let path = self.file(&run, old_name, extension);
+                match fs::rename(&path, self.file(&run, new_name, extension)) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => {
+                        return Err(vec![Error::Io(e, path.display().to_string())]);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Only keep the backups of the `keep` most recent runs.
+    pub fn prune(&self, keep: usize) -> Result<(), Vec<Error>> {
+        let runs = self.runs()?;
+        let old = runs.len().saturating_sub(keep);
+        for run in &runs[..old] {
+            let run_dir = self.dir.join(run);
+            fs::remove_dir_all(&run_dir)
+                .map_err(|e| vec![Error::Io(e, run_dir.display().to_string())])?;
+        }
+        Ok(())
+    }
+
+    /// Return the path of a file of the given run.
+    fn file(&self, run: &str, name: &str, extension: &str) -> PathBuf {
+        self.dir
+            .join(run)
+            .join(format!("{}.{}", encode(name), extension))
+    }
+
+    /// Return the names of the entries of a directory, which may not exist.
+    fn entries(dir: &Path) -> Result<Vec<String>, Vec<Error>> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(vec![Error::Io(e, dir.display().to_string())]),
+        };
+        entries
+            .map(|entry| {
+                entry
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .map_err(|e| vec![Error::Io(e, dir.display().to_string())])
+            })
+            .collect()
+    }
+}
+
+/// Percent-encode every byte of a flake name which is not alphanumeric, `-` or `_`,
+/// so that it can be used as a file name.
+fn encode(name: &str) -> String {
+    name.bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => char::from(byte).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Decode a flake name encoded by `encode`.
+fn decode(stem: &str) -> String {
+    let mut bytes = Vec::with_capacity(stem.len());
+    let mut rest = stem.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let hex = tail.get(..2).and_then(|hex| std::str::from_utf8(hex).ok());
+        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(decoded) if byte == b'%' => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
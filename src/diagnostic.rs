@@ -0,0 +1,720 @@
+//! The errors and warnings reported by nix.
+//!
+//! Nix is run with `--log-format internal-json`, so that each message is a JSON
+//! event on its own line, prefixed by `@nix`, carrying its level, its position
+//! and its trace. Lines which are not such events, printed by older versions of
+//! nix or by the programs nix runs, are parsed as text instead.
+
+use std::fmt;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+/// The prefix of the lines holding a JSON event.
+const EVENT_PREFIX: &str = "@nix ";
+
+/// The levels of the messages of nix, as numbered by `--log-format internal-json`.
+const LEVEL_ERROR: u8 = 0;
+const LEVEL_WARNING: u8 = 1;
+const LEVEL_INFO: u8 = 3;
+
+/// What a failure is about, guessed from its message.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Fetching an input failed, usually because of the network.
+    Fetch,
+    /// Evaluating the flake failed.
+    Evaluation,
+    /// The lock file is inconsistent with the flake, or cannot be written.
+    LockConflict,
+    /// A file or the store cannot be accessed.
+    Permission,
+    Other,
+}
+
+/// A position in a nix file.
+#[derive(Clone)]
+pub struct Position {
+    pub file: String,
+    pub line: u64,
+    pub column: u64,
+}
+
+/// A step of the trace of an error, in the order nix prints them.
+#[derive(Clone)]
+pub struct Trace {
+    pub message: String,
+    pub position: Option<Position>,
+}
+
+/// An error or a warning reported by nix.
+#[derive(Clone)]
+pub struct Diagnostic {
+    pub kind: Kind,
+    pub message: String,
+    pub position: Option<Position>,
+    pub trace: Vec<Trace>,
+}
+
+/// A message reported by nix.
+pub enum Message {
+    Error(Diagnostic),
+    Warning(Diagnostic),
+}
+
+/// A JSON event. Only the `msg` events are of interest.
+#[derive(Deserialize)]
+struct Event {
+    action: String,
+    #[serde(default)]
+    level: Option<u8>,
+    #[serde(default)]
+    msg: Option<String>,
+    /// The description of the activity, for the `start` events.
+    #[serde(default)]
+    text: Option<String>,
+    /// The message without its level and trace, if it is an error with a position.
+    #[serde(default)]
+    raw_msg: Option<String>,
+    #[serde(flatten)]
+    position: RawPosition,
+    #[serde(default)]
+    trace: Vec<RawTrace>,
+}
+
+#[derive(Deserialize)]
+struct RawTrace {
+    raw_msg: String,
+    #[serde(flatten)]
+    position: RawPosition,
+}
+
+/// A position, whose file may be absent, or something else than a path.
+#[derive(Deserialize)]
+struct RawPosition {
+    #[serde(default)]
+    file: Option<Value>,
+    #[serde(default)]
+    line: Option<u64>,
+    #[serde(default)]
+    column: Option<u64>,
+}
+
+impl RawPosition {
+    fn into_position(self) -> Option<Position> {
+        Some(Position {
+            file: self.file?.as_str()?.to_owned(),
+            line: self.line?,
+            column: self.column.unwrap_or(0),
+        })
+    }
+}
+
+/// Return what to show of a line of the standard error of nix while it runs, if anything:
+/// the activities it starts, such as fetching an input, and its informational messages.
+/// Errors and warnings are left out, as they are reported once nix is done.
+/// With `verbose`, every message is shown, as well as the lines which are not JSON events.
+pub fn progress(line: &str, verbose: bool) -> Option<String> {
+    let Some(event) = line
+        .strip_prefix(EVENT_PREFIX)
+        .and_then(|event| serde_json::from_str::<Event>(event).ok())
+    else {
+        let line = strip_ansi(line);
+        return (verbose && !line.trim().is_empty()).then_some(line);
+    };
+    let level = event.level.unwrap_or(LEVEL_ERROR);
+    if level <= LEVEL_WARNING || (level > LEVEL_INFO && !verbose) {
+        return None;
+    }
+    let text = match event.action.as_str() {
+        "msg" => event.msg?,
+        "start" => event.text?,
+        _ => return None,
+    };
+    let text = strip_ansi(&text);
+    (!text.trim().is_empty()).then_some(text)
+}
+
+/// Parse the standard error of nix.
+pub fn parse(stderr: &[u8]) -> Vec<Message> {
+    let stderr = String::from_utf8_lossy(stderr);
+    let mut messages = Vec::new();
+    // The text error being read, to which the following lines belong.
+    let mut current: Option<Diagnostic> = None;
+
+    for line in stderr.lines() {
+        if let Some(event) = line
+            .strip_prefix(EVENT_PREFIX)
+            .and_then(|event| serde_json::from_str::<Event>(event).ok())
+        {
+            messages.extend(current.take().map(Message::Error));
+            if event.action != "msg" {
+                continue;
+            }
+            let Some(msg) = event.msg.as_deref().map(strip_ansi) else {
+                continue;
+            };
+            let level = event.level.unwrap_or(LEVEL_ERROR);
+            if level > LEVEL_WARNING {
+                continue;
+            }
+            let message = match &event.raw_msg {
+                Some(raw_msg) => strip_ansi(raw_msg).trim().to_owned(),
+                None => strip_level(&msg).trim().to_owned(),
+            };
+            let trace = event
+                .trace
+                .into_iter()
+                .map(|trace| Trace {
+                    message: strip_ansi(&trace.raw_msg).trim().to_owned(),
+                    position: trace.position.into_position(),
+                })
+                .collect();
+            let diagnostic = Diagnostic::new(message, event.position.into_position(), trace);
+            messages.push(match level {
+                LEVEL_ERROR => Message::Error(diagnostic),
+                _ => Message::Warning(diagnostic),
+            });
+            continue;
+        }
+
+        let line = strip_ansi(line);
+        if let Some(message) = line.strip_prefix("error:") {
+            messages.extend(current.take().map(Message::Error));
+            current = Some(Diagnostic::new(message.trim().to_owned(), None, Vec::new()));
+        } else if let Some(message) = line.strip_prefix("warning:") {
+            messages.extend(current.take().map(Message::Error));
+            let diagnostic = Diagnostic::new(message.trim().to_owned(), None, Vec::new());
+            messages.push(Message::Warning(diagnostic));
+        } else if line.trim().is_empty() {
+            continue;
+        } else if let Some(error) = current.as_mut() {
+            error.push_text(line.trim());
+        } else {
+            let diagnostic = Diagnostic::new(line.trim().to_owned(), None, Vec::new());
+            messages.push(Message::Warning(diagnostic));
+        }
+    }
+    messages.extend(current.take().map(Message::Error));
+
+    messages
+}
+
+impl Diagnostic {
+    pub fn new(message: String, position: Option<Position>, trace: Vec<Trace>) -> Self {
+        let mut diagnostic = Diagnostic {
+            kind: Kind::Other,
+            message,
+            position,
+            trace,
+        };
+        diagnostic.kind = diagnostic.classify();
+        diagnostic
+    }
+
+    /// Add a line following a text error: its position, a step of its trace, or the rest of its message.
+    fn push_text(&mut self, line: &str) {
+        if let Some(message) = line.strip_prefix("error:") {
+            // Since Nix 2.13, the message follows the trace, on an indented line.
+            if !self.message.is_empty() {
+                self.message.push('\n');
+            }
+            self.message.push_str(message.trim());
+        } else if let Some(position) = line.strip_prefix("at ").and_then(parse_position) {
+            match self.trace.last_mut() {
+                Some(trace) if trace.position.is_none() => trace.position = Some(position),
+                Some(_) => {}
+                None if self.position.is_none() => self.position = Some(position),
+                None => {}
+            }
+        } else if let Some(message) = line.strip_prefix('…') {
+            self.trace.push(Trace {
+                message: message.trim().to_owned(),
+                position: None,
+            });
+        } else if self.trace.is_empty() && self.position.is_none() {
+            self.message.push('\n');
+            self.message.push_str(line);
+        }
+        self.kind = self.classify();
+    }
+
+    /// Wether the failure is likely to go away by trying again later: a server
+    /// error, a rate limit, or a network failure, as opposed to a missing input.
+    /// It is decided from the message alone, which is the innermost error, whatever its kind.
+    pub fn is_transient(&self) -> bool {
+        mentions(
+            &self.message,
+            &[
+                "http error 5",
+                "http error 429",
+                "returned error: 5",
+                "returned error: 429",
+                "too many requests",
+                "rate limit",
+                "could not resolve host",
+                "couldn't resolve host",
+                "temporary failure in name resolution",
+                "failed to connect",
+                "connection reset",
+                "connection refused",
+                "timed out",
+                "timeout was reached",
+            ],
+        )
+    }
+
+    /// Guess what the failure is about from its message, which is the innermost
+    /// error, and only from its trace if the message tells nothing.
+    fn classify(&self) -> Kind {
+        let kind = classify_message(&self.message);
+        if kind != Kind::Other {
+            return kind;
+        }
+        // Nix wraps every failure of an update in the same frames, which tell nothing about it.
+        let mut trace = self
+            .trace
+            .iter()
+            .map(|trace| trace.message.as_str())
+            .filter(|message| {
+                !mentions(
+                    message,
+                    &[
+                        "while updating the lock file",
+                        "while updating the flake input",
+                    ],
+                )
+            })
+            .peekable();
+        if trace.peek().is_none() {
+            return Kind::Other;
+        }
+        trace
+            .map(classify_frame)
+            .find(|kind| *kind != Kind::Other)
+            .unwrap_or(Kind::Evaluation)
+    }
+}
+
+/// Guess what a failure is about from its message. Only the message can tell
+/// that the lock file is at fault, since the trace of every update mentions it.
+fn classify_message(message: &str) -> Kind {
+    match classify_frame(message) {
+        Kind::Permission => Kind::Permission,
+        _ if mentions(
+            message,
+            &[
+                "lock file",
+                "follows a non-existent input",
+                "override for a non-existent input",
+            ],
+        ) =>
+        {
+            Kind::LockConflict
+        }
+        kind => kind,
+    }
+}
+
+/// Guess what a failure is about from its message or a step of its trace.
+fn classify_frame(text: &str) -> Kind {
+    if mentions(
+        text,
+        &[
+            "permission denied",
+            "operation not permitted",
+            "read-only file system",
+            "is not allowed to",
+        ],
+    ) {
+        Kind::Permission
+    } else if mentions(
+        text,
+        &[
+            "unable to download",
+            "http error",
+            "could not resolve host",
+            "couldn't resolve host",
+            "failed to connect",
+            "connection reset",
+            "connection refused",
+            "timed out",
+            "unable to fetch",
+            "cannot fetch",
+            "failed to fetch",
+            "while fetching",
+        ],
+    ) {
+        Kind::Fetch
+    } else if mentions(
+        text,
+        &[
+            "while evaluating",
+            "undefined variable",
+            "syntax error",
+            "infinite recursion",
+            "attribute '",
+            "called with unexpected argument",
+            "called without required argument",
+            "cannot coerce",
+            "evaluation aborted",
+        ],
+    ) {
+        Kind::Evaluation
+    } else {
+        Kind::Other
+    }
+}
+
+/// Wether a text contains one of the given lowercase patterns.
+fn mentions(text: &str, patterns: &[&str]) -> bool {
+    let text = text.to_lowercase();
+    patterns.iter().any(|pattern| text.contains(pattern))
+}
+
+/// Parse a position such as `/path/to/flake.nix:3:1:`.
+fn parse_position(text: &str) -> Option<Position> {
+    let text = text.trim().trim_end_matches(':');
+    let (rest, column) = text.rsplit_once(':')?;
+    let (file, line) = rest.rsplit_once(':')?;
+    Some(Position {
+        file: file.to_owned(),
+        line: line.parse().ok()?,
+        column: column.parse().ok()?,
+    })
+}
+
+/// Remove the level prefixing a message, such as `error:`.
+fn strip_level(msg: &str) -> &str {
+    ["error:", "warning:"]
+        .iter()
+        .find_map(|prefix| msg.strip_prefix(prefix))
+        .unwrap_or(msg)
+}
+
+/// Remove the ANSI escape codes from a text.
+fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Control sequences end with a letter.
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if c.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Kind::Fetch => "fetch",
+            Kind::Evaluation => "evaluation",
+            Kind::LockConflict => "lock file",
+            Kind::Permission => "permission",
+            Kind::Other => "nix",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(position) = &self.position {
+            write!(f, "\n  at {}", position)?;
+        }
+        for trace in &self.trace {
+            write!(f, "\n  … {}", trace.message)?;
+            if let Some(position) = &trace.position {
+                write!(f, "\n    at {}", position)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse `stderr`, which must only hold errors.
+    fn errors(stderr: &str) -> Vec<Diagnostic> {
+        parse(stderr.as_bytes())
+            .into_iter()
+            .map(|message| match message {
+                Message::Error(diagnostic) => diagnostic,
+                Message::Warning(diagnostic) => panic!("unexpected warning: {}", diagnostic),
+            })
+            .collect()
+    }
+
+    /// Parse `stderr`, which must hold a single error.
+    fn error(stderr: &str) -> Diagnostic {
+        let mut errors = errors(stderr);
+        assert_eq!(errors.len(), 1);
+        errors.remove(0)
+    }
+
+    #[test]
+    fn text_traces() {
+        // Since Nix 2.13, the trace comes first, and the message last.
+        let diagnostic = error(
+            "error:
+       … while calling the 'derivationStrict' builtin
+
+       … while evaluating the attribute 'packages'
+         at /tmp/system/flake.nix:3:5:
+
+            2|   outputs = _: {
+            3|     packages = x;
+             |     ^
+
+       error: undefined variable 'x'
+",
+        );
+        assert!(diagnostic.kind == Kind::Evaluation);
+        assert_eq!(diagnostic.message, "undefined variable 'x'");
+        assert!(diagnostic.position.is_none());
+        assert_eq!(diagnostic.trace.len(), 2);
+        assert_eq!(
+            diagnostic.trace[0].message,
+            "while calling the 'derivationStrict' builtin"
+        );
+        assert!(diagnostic.trace[0].position.is_none());
+        assert_eq!(
+            diagnostic.trace[1].message,
+            "while evaluating the attribute 'packages'"
+        );
+        let position = diagnostic.trace[1].position.as_ref().unwrap();
+        assert_eq!(position.to_string(), "/tmp/system/flake.nix:3:5");
+        assert_eq!(
+            diagnostic.to_string(),
+            "undefined variable 'x'\n  … while calling the 'derivationStrict' builtin\n  \
+             … while evaluating the attribute 'packages'\n    at /tmp/system/flake.nix:3:5"
+        );
+    }
+
+    #[test]
+    fn text_positions_and_messages() {
+        // Before Nix 2.13, the position follows the message.
+        let diagnostic = error(
+            "error: syntax error, unexpected end of file
+
+       at /tmp/my:flake/flake.nix:3:1:
+
+            2| {
+            3|
+             | ^
+",
+        );
+        assert!(diagnostic.kind == Kind::Evaluation);
+        assert_eq!(diagnostic.message, "syntax error, unexpected end of file");
+        let position = diagnostic.position.unwrap();
+        assert_eq!(position.file, "/tmp/my:flake/flake.nix");
+        assert_eq!((position.line, position.column), (3, 1));
+        assert!(diagnostic.trace.is_empty());
+
+        // The lines of a message without position belong to it, until the next error.
+        let errors = errors(
+            "error: unable to download 'https://example.org/a.tar.gz': HTTP error 404
+
+       response body:
+
+       Not Found
+error: some other error
+",
+        );
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[0].message,
+            "unable to download 'https://example.org/a.tar.gz': HTTP error 404\n\
+             response body:\nNot Found"
+        );
+        assert!(errors[0].kind == Kind::Fetch);
+        assert_eq!(errors[1].message, "some other error");
+    }
+
+    #[test]
+    fn ansi_colours_are_stripped() {
+        let messages = parse(
+            b"\x1b[35;1mwarning:\x1b[0m Git tree '/tmp/system' is dirty\n\
+              \x1b[31;1merror:\x1b[0m unable to download '\x1b[35;1mhttps://example.org/a.tar.gz\x1b[0m': HTTP error 503\n",
+        );
+        assert_eq!(messages.len(), 2);
+        let Message::Warning(warning) = &messages[0] else {
+            panic!("the first message is not a warning");
+        };
+        assert_eq!(warning.message, "Git tree '/tmp/system' is dirty");
+        let Message::Error(error) = &messages[1] else {
+            panic!("the second message is not an error");
+        };
+        assert_eq!(
+            error.message,
+            "unable to download 'https://example.org/a.tar.gz': HTTP error 503"
+        );
+        assert!(error.kind == Kind::Fetch);
+    }
+
+    #[test]
+    fn json_events() {
+        let messages = parse(
+            br#"@nix {"action":"start","id":1,"level":4,"text":"evaluating flake","type":0}
+@nix {"action":"msg","level":1,"msg":"warning: Git tree is dirty"}
+@nix {"action":"msg","level":0,"msg":"error: undefined variable 'hello'","raw_msg":"undefined variable 'hello'","file":"/tmp/flake.nix","line":4,"column":12,"trace":[{"raw_msg":"while evaluating 'packages'","file":{"source":"string"},"line":3}]}
+"#,
+        );
+        assert_eq!(messages.len(), 2);
+        assert!(
+            matches!(&messages[0], Message::Warning(warning) if warning.message == "Git tree is dirty")
+        );
+        let Message::Error(error) = &messages[1] else {
+            panic!("the second message is not an error");
+        };
+        assert_eq!(error.message, "undefined variable 'hello'");
+        assert_eq!(
+            error.position.as_ref().unwrap().to_string(),
+            "/tmp/flake.nix:4:12"
+        );
+        // A position whose file is not a path is dropped.
+        assert!(error.trace[0].position.is_none());
+        assert!(error.kind == Kind::Evaluation);
+    }
+
+    #[test]
+    fn progress_lines() {
+        let start = r#"@nix {"action":"start","id":1,"level":3,"text":"fetching 'github:NixOS/nixpkgs'","type":0}"#;
+        let debug = r#"@nix {"action":"msg","level":5,"msg":"\u001b[1mdebug\u001b[0m message"}"#;
+        let warning = r#"@nix {"action":"msg","level":1,"msg":"warning: Git tree is dirty"}"#;
+        assert_eq!(
+            progress(start, false).as_deref(),
+            Some("fetching 'github:NixOS/nixpkgs'")
+        );
+        assert_eq!(progress(debug, false), None);
+        assert_eq!(progress(debug, true).as_deref(), Some("debug message"));
+        assert_eq!(progress(warning, true), None);
+        assert_eq!(progress("copying path", false), None);
+        assert_eq!(
+            progress("copying path", true).as_deref(),
+            Some("copying path")
+        );
+        assert_eq!(progress("  ", true), None);
+    }
+
+    #[test]
+    fn transient_failures() {
+        let transient = [
+            "unable to download 'https://example.org/a.tar.gz': HTTP error 503",
+            "unable to download 'https://api.github.com/repos/NixOS/nixpkgs': HTTP error 429",
+            "unable to download 'https://github.com/a.tar.gz': Couldn't resolve host name (6)",
+            "unable to download 'https://github.com/a.tar.gz': Timeout was reached (28)",
+            "failed to fetch 'https://github.com': Could not resolve host: github.com",
+        ];
+        for message in transient {
+            let diagnostic = error(&format!("error: {}\n", message));
+            assert!(diagnostic.kind == Kind::Fetch, "{}", message);
+            assert!(diagnostic.is_transient(), "{}", message);
+        }
+
+        // Whatever the kind of the failure.
+        let diagnostic =
+            error("error: program 'git' failed: The requested URL returned error: 503\n");
+        assert!(diagnostic.kind == Kind::Other);
+        assert!(diagnostic.is_transient());
+
+        let permanent = [
+            "unable to download 'https://example.org/a.tar.gz': HTTP error 404",
+            "unable to download 'https://example.org/a.tar.gz': HTTP error 401",
+            "undefined variable 'x'",
+            "cannot write modified lock file of flake 'path:/tmp' (use '--no-write-lock-file' to ignore)",
+        ];
+        for message in permanent {
+            assert!(
+                !error(&format!("error: {}\n", message)).is_transient(),
+                "{}",
+                message
+            );
+        }
+    }
+
+    /// Return the standard error of `nix flake update` failing with `message`,
+    /// with the trace nix prints around it.
+    fn update_failure(message: &str) -> String {
+        format!(
+            "error:
+       … while updating the lock file of flake 'path:/tmp/system?lastModified=1700000000&narHash=sha256-abc'
+
+       … while updating the flake input 'nixpkgs'
+
+       … while fetching the input 'github:NixOS/nixpkgs/nixos-unstable'
+
+       error: {}
+",
+            message
+        )
+    }
+
+    #[test]
+    fn update_failures() {
+        let diagnostic = error(&update_failure(
+            "unable to download 'https://api.github.com/repos/NixOS/nixpkgs/commits/nixos-unstable': HTTP error 503",
+        ));
+        assert_eq!(diagnostic.trace.len(), 3);
+        assert!(diagnostic.kind == Kind::Fetch);
+        assert!(diagnostic.is_transient());
+
+        let diagnostic = error(&update_failure(
+            "unable to download 'https://github.com/NixOS/nixpkgs/archive/abc.tar.gz': HTTP error 404",
+        ));
+        assert!(diagnostic.kind == Kind::Fetch);
+        assert!(!diagnostic.is_transient());
+
+        // The trace is only used when the message tells nothing.
+        let diagnostic = error(&update_failure("repository 'NixOS/nixpkgs' does not exist"));
+        assert!(diagnostic.kind == Kind::Fetch);
+        let diagnostic = error(&update_failure("undefined variable 'x'"));
+        assert!(diagnostic.kind == Kind::Evaluation);
+        let diagnostic = error(&update_failure(
+            "cannot write modified lock file of flake 'path:/tmp/system' (use '--no-write-lock-file' to ignore)",
+        ));
+        assert!(diagnostic.kind == Kind::LockConflict);
+
+        // The frames of every update tell nothing.
+        let diagnostic = error(
+            "error:
+       … while updating the lock file of flake 'path:/tmp/system'
+
+       … while updating the flake input 'nixpkgs'
+
+       error: something went wrong
+",
+        );
+        assert!(diagnostic.kind == Kind::Other);
+    }
+
+    #[test]
+    fn failures_are_classified() {
+        let kind = |message: &str| error(&format!("error: {}\n", message)).kind;
+        assert!(
+            kind("opening lock file '/nix/var/nix/db/big-lock': Permission denied")
+                == Kind::Permission
+        );
+        assert!(kind("input 'nixpkgs' follows a non-existent input 'foo'") == Kind::LockConflict);
+        assert!(kind("infinite recursion encountered") == Kind::Evaluation);
+        assert!(kind("path '/tmp/system' does not contain a 'flake.nix'") == Kind::Other);
+    }
+}
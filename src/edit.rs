@@ -0,0 +1,183 @@
+//! Edition of the registry in a text editor.
+//!
+//! The registry is copied next to itself, and the copy is opened in the editor.
+//! It replaces the registry only once it is valid: otherwise its errors are
+//! reported, with their line, and the editor is opened again. Besides being
+//! valid TOML, it must not have fields SnowPlow does not know, which would be
+//! dropped silently, and the path of every flake must hold a `flake.nix`. The
+//! relative paths are made absolute, as when a flake is added.
+
+use std::{
+    collections::BTreeSet,
+    env, fs,
+    path::{self, Path},
+    process::Command,
+};
+
+use crate::{error, registry::Registry, warn, Error, Flake, Interface};
+
+/// The editor used when neither `VISUAL` nor `EDITOR` is set.
+const DEFAULT_EDITOR: &str = "vi";
+
+impl Interface {
+    pub(crate) fn edit_registry(&mut self) -> Result<(), Vec<Error>> {
+        let editor = env::var("VISUAL")
+            .or_else(|_| env::var("EDITOR"))
+            .unwrap_or_else(|_| DEFAULT_EDITOR.to_owned());
+        let edit_path = self.config_path.with_extension("edit.toml");
+        self.registry().save(&edit_path)?;
+        let result = self.edit_until_valid(&editor, &edit_path);
+        let _ = fs::remove_file(&edit_path);
+
+        let registry = result?;
+        self.settings = registry.settings;
+        self.hub = registry.hub;
+        self.flakes = registry.flakes.into_iter().map(Into::into).collect();
+        Ok(())
+    }
+
+    /// Open the file at `path` with `editor` until it holds a valid registry, and return it.
+    fn edit_until_valid(&self, editor: &str, path: &Path) -> Result<Registry, Vec<Error>> {
+        // The editor may be given with arguments, such as `code --wait`.
+        let mut words = editor.split_whitespace();
+        let program = words.next().unwrap_or(DEFAULT_EDITOR);
+        let args: Vec<&str> = words.collect();
+        loop {
+            let status = Command::new(program)
+                .args(&args)
+                .arg(path)
+                .status()
+                .map_err(|e| vec![Error::Io(e, program.to_owned())])?;
+            if !status.success() {
+                return Err(vec![Error::Editor(editor.to_owned())]);
+            }
+
+            let content = fs::read_to_string(path)
+                .map_err(|e| vec![Error::Io(e, path.display().to_string())])?;
+            match parse(&content, path) {
+                Ok(registry) => return Ok(registry),
+                Err(errors) => {
+                    for err in errors {
+                        error(&err.msg(), self.stderr_style);
+                    }
+                    warn(
+                        "the registry is not valid, it is opened again",
+                        self.stderr_style,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Parse an edited registry, rejecting the unknown fields, the flakes present
+/// several times, which would otherwise be dropped silently, and the flakes
+/// whose path holds no `flake.nix`, and make the paths absolute.
+fn parse(content: &str, path: &Path) -> Result<Registry, Vec<Error>> {
+    let parse_error =
+        |e: toml::de::Error| vec![Error::Parse(path.display().to_string(), e.to_string())];
+    let mut unknown = Vec::new();
+    let registry: Registry =
+        serde_ignored::deserialize(toml::Deserializer::new(content), |field| {
+            unknown.push((None, field.to_string()))
+        })
+        .map_err(parse_error)?;
+    let mut registry = registry.upgrade()?;
+
+    // The fields of the flakes are flattened, so that the unknown ones are not
+    // reported above: each flake is parsed again on its own.
+    let table: toml::Table = content.parse().map_err(parse_error)?;
+    if let Some(toml::Value::Array(flakes)) = table.get("flake") {
+        for (i, flake) in flakes.iter().enumerate() {
+            let mut flake = flake.clone();
+            if let toml::Value::Table(fields) = &mut flake {
+                fields.remove("name");
+            }
+            let _: Result<Flake, _> = serde_ignored::deserialize(flake, |field| {
+                unknown.push((Some(i), field.to_string()))
+            });
+        }
+    }
+
+    let error = |line: Option<usize>, msg: String| {
+        let line = line
+            .map(|line| format!("line {}: ", line))
+            .unwrap_or_default();
+        Error::Parse(path.display().to_string(), format!("{}{}", line, msg))
+    };
+    let mut errors = Vec::new();
+    for (flake, field) in unknown {
+        let key = field.rsplit('.').next().unwrap_or(&field);
+        let msg = match flake {
+            Some(i) => format!(
+                "unknown field `{}` in flake `{}`",
+                field, registry.flakes[i].name
+            ),
+            None => format!("unknown field `{}`", field),
+        };
+        errors.push(error(key_line(content, flake, key), msg));
+    }
+
+    let mut names = BTreeSet::new();
+    for (i, named_flake) in registry.flakes.iter_mut().enumerate() {
+        let name = &named_flake.name;
+        if !names.insert(name) {
+            let line = name_lines(content, name).nth(1);
+            errors.push(error(
+                line,
+                format!("flake `{}` is present several times", name),
+            ));
+        }
+        // SnowPlow runs from anywhere, so the path must not be relative to where it was edited.
+        match path::absolute(&named_flake.flake.path) {
+            Ok(absolute) => named_flake.flake.path = absolute,
+            Err(e) => {
+                let path = named_flake.flake.path.display().to_string();
+                errors.push(Error::Io(e, path));
+                continue;
+            }
+        }
+        if !named_flake.flake.path.join("flake.nix").is_file() {
+            let msg = format!(
+                "`{}` is not a flake, it has no `flake.nix`",
+                named_flake.flake.path.display()
+            );
+            errors.push(error(key_line(content, Some(i), "path"), msg));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(registry)
+}
+
+/// Return the number of the line setting `key`, in the `i`th flake if any.
+fn key_line(content: &str, flake: Option<usize>, key: &str) -> Option<usize> {
+    let mut lines = content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()));
+    if let Some(flake) = flake {
+        lines
+            .by_ref()
+            .filter(|(_, line)| *line == "[[flake]]")
+            .nth(flake)?;
+    }
+    lines
+        .take_while(|(_, line)| flake.is_none() || !line.starts_with('['))
+        .find(|(_, line)| {
+            line.split('=')
+                .next()
+                .is_some_and(|name| name.trim().trim_matches(|c| c == '"' || c == '\'') == key)
+        })
+        .map(|(number, _)| number)
+}
+
+/// Return the numbers of the lines giving a flake its name.
+fn name_lines<'a>(content: &'a str, name: &'a str) -> impl Iterator<Item = usize> + 'a {
+    content.lines().enumerate().filter_map(move |(i, line)| {
+        let line: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+        let value = line.strip_prefix("name=")?;
+        (value.trim_matches(|c| c == '"' || c == '\'') == name).then_some(i + 1)
+    })
+}
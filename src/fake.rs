@@ -0,0 +1,234 @@
+//! A fake nix, replaying a script, so that SnowPlow can be tested without nix nor network access.
+//!
+//! It is only built in debug builds, and selected by giving the path of a script
+//! through the environment variable `SNOW_PLOW_FAKE_NIX`. The script is a
+//! TOML file describing what each operation does on each flake, the flakes being
+//! identified by the name of their directory, or by the name of the flake for
+//! the temporary work trees of `update --branch`:
+//!
+//! ```toml
+//! version = "nix (Nix) 2.24.1"
+//! # Every operation is appended to this file, one per line.
+//! log = "/tmp/calls.log"
+//!
+//! [flakes.my-flake.update]
+//! # Written to the lock file of the flake.
+//! lock = '{"nodes": {"root": {}}, "root": "root", "version": 7}'
+//!
+//! [flakes.other.check]
+//! success = false
+//! stderr = "error: getting status of '/nix/store/...': No such file or directory"
+//! ```
+//!
+//! An operation which is not described succeeds without output.
+
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    backend::{Backend, Control, Output},
+    lock::LOCK_FILE,
+    nix::NixVersion,
+    update::WORKTREE_PREFIX,
+    Error, Verification,
+};
+
+/// The backend replaying a script.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FakeBackend {
+    /// The output of `nix --version`.
+    #[serde(default = "default_version")]
+    version: String,
+    /// The file in which every operation is recorded.
+    log: Option<PathBuf>,
+    /// What the operations do, by name of the directory of the flake.
+    #[serde(default)]
+    flakes: HashMap<String, FakeFlake>,
+}
+
+/// What the operations do on a flake.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FakeFlake {
+    check: Option<Action>,
+    metadata: Option<Action>,
+    lock: Option<Action>,
+    update: Option<Action>,
+    verify: Option<Action>,
+}
+
+/// What an operation does.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Action {
+    /// Wether the operation succeeds.
+    success: bool,
+    stdout: String,
+    stderr: String,
+    /// The content written to the lock file of the flake.
+    lock: Option<String>,
+    /// How long the operation lasts, in seconds, the lock file being written before.
+    sleep: f64,
+    /// Make SnowPlow panic, to test how it copes with its own bugs.
+    panic: bool,
+}
+
+impl Default for Action {
+    fn default() -> Self {
+        Action {
+            success: true,
+            stdout: String::new(),
+            stderr: String::new(),
+            lock: None,
+            sleep: 0.0,
+            panic: false,
+        }
+    }
+}
+
+fn default_version() -> String {
+    "nix (Nix) 2.24.1".to_owned()
+}
+
+impl FakeBackend {
+    /// Read the script at `path`.
+    pub fn load(path: &Path) -> Result<Self, Vec<Error>> {
+        let content =
+            fs::read_to_string(path).map_err(|e| vec![Error::Io(e, path.display().to_string())])?;
+        toml::from_str(&content)
+            .map_err(|e| vec![Error::Parse(path.display().to_string(), e.to_string())])
+    }
+
+    /// Return the name under which the flake at `path` is described.
+    fn flake_name(&self, path: &Path) -> String {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        // The work trees are named `snow-plow-<run>-<name>`, and the run has dashes too.
+        if name.starts_with(WORKTREE_PREFIX) {
+            let flake = self
+                .flakes
+                .keys()
+                .filter(|flake| name.ends_with(&format!("-{}", flake)))
+                .max_by_key(|flake| flake.len());
+            if let Some(flake) = flake {
+                return flake.clone();
+            }
+        }
+        name
+    }
+
+    /// Record an operation, and replay what the script says it does.
+    fn replay(
+        &self,
+        operation: &str,
+        path: &Path,
+        details: &[String],
+        action: impl FnOnce(&FakeFlake) -> Option<&Action>,
+        control: &mut Control,
+    ) -> Result<Output, Vec<Error>> {
+        let name = self.flake_name(path);
+
+        if let Some(log) = &self.log {
+            let mut line = format!("{} {}", operation, name);
+            for detail in details {
+                line.push(' ');
+                line.push_str(detail);
+            }
+            line.push('\n');
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log)
+                .and_then(|mut file| file.write_all(line.as_bytes()))
+                .map_err(|e| vec![Error::Io(e, log.display().to_string())])?;
+        }
+
+        let Some(action) = self.flakes.get(&name).and_then(action) else {
+            return Ok(Output {
+                success: true,
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            });
+        };
+        if action.panic {
+            panic!("the fake nix panicked while running `{} {}`", operation, name);
+        }
+        for line in action.stderr.lines() {
+            (control.progress)(line);
+        }
+        if let Some(lock) = &action.lock {
+            let lock_path = path.join(LOCK_FILE);
+            fs::write(&lock_path, lock)
+                .map_err(|e| vec![Error::Io(e, lock_path.display().to_string())])?;
+        }
+        // Pretend that nix runs for a while, and stop as the real one would.
+        control.wait(Duration::from_secs_f64(action.sleep))?;
+        Ok(Output {
+            success: action.success,
+            stdout: action.stdout.clone().into_bytes(),
+            stderr: action.stderr.clone().into_bytes(),
+        })
+    }
+}
+
+impl Backend for FakeBackend {
+    fn version(&self) -> Result<NixVersion, Vec<Error>> {
+        NixVersion::from_output(&self.version)
+    }
+
+    fn check(&self, path: &Path, control: &mut Control) -> Result<Output, Vec<Error>> {
+        self.replay("check", path, &[], |flake| flake.check.as_ref(), control)
+    }
+
+    fn metadata(
+        &self,
+        path: &Path,
+        control: &mut Control,
+    ) -> Result<Output, Vec<Error>> {
+        self.replay("metadata", path, &[], |flake| flake.metadata.as_ref(), control)
+    }
+
+    fn lock(
+        &self,
+        path: &Path,
+        args: &[String],
+        control: &mut Control,
+    ) -> Result<Output, Vec<Error>> {
+        self.replay("lock", path, args, |flake| flake.lock.as_ref(), control)
+    }
+
+    fn update(
+        &self,
+        path: &Path,
+        inputs: &[String],
+        args: &[String],
+        control: &mut Control,
+    ) -> Result<Output, Vec<Error>> {
+        let details: Vec<String> = inputs.iter().chain(args).cloned().collect();
+        self.replay("update", path, &details, |flake| flake.update.as_ref(), control)
+    }
+
+    fn verify(
+        &self,
+        path: &Path,
+        step: &Verification,
+        control: &mut Control,
+    ) -> Result<Output, Vec<Error>> {
+        let step = match step {
+            Verification::Check => "check".to_owned(),
+            Verification::Eval(attr) => format!("eval {}", attr),
+            Verification::Build(output) => format!("build {}", output),
+        };
+        self.replay("verify", path, &[step], |flake| flake.verify.as_ref(), control)
+    }
+}
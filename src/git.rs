@@ -0,0 +1,96 @@
+//! Committing the lock files of flakes living in git repositories.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    lock::{InputChange, LOCK_FILE},
+    Error,
+};
+
+/// Run git in `dir` with the given arguments, and return its standard output.
+fn git(dir: &Path, args: &[&str]) -> Result<String, Vec<Error>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .map_err(|e| vec![Error::Io(e, "git".into())])?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(vec![Error::Git(stderr.trim().to_owned())]);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Wether `dir` is inside a git work tree.
+pub fn is_repository(dir: &Path) -> bool {
+    git(dir, &["rev-parse", "--is-inside-work-tree"]).is_ok_and(|out| out.trim() == "true")
+}
+
+/// Wether the lock file of the flake in `flake_dir` has uncommitted changes.
+pub fn is_lock_dirty(flake_dir: &Path) -> Result<bool, Vec<Error>> {
+    let status = git(flake_dir, &["status", "--porcelain", "--", LOCK_FILE])?;
+    Ok(!status.trim().is_empty())
+}
+
+/// Commit the lock file of the flake in `flake_dir`, and only it.
+pub fn commit_lock(flake_dir: &Path, message: &str) -> Result<(), Vec<Error>> {
+    git(flake_dir, &["add", "--", LOCK_FILE])?;
+    git(flake_dir, &["commit", "--quiet", "-m", message, "--", LOCK_FILE])?;
+    Ok(())
+}
+
+/// Unstage the lock file of the flake in `flake_dir`, after its commit failed.
+pub fn unstage_lock(flake_dir: &Path) -> Result<(), Vec<Error>> {
+    git(flake_dir, &["reset", "--quiet", "--", LOCK_FILE])?;
+    Ok(())
+}
+
+/// Return the path of `dir` relative to the root of its work tree.
+pub fn prefix(dir: &Path) -> Result<PathBuf, Vec<Error>> {
+    Ok(PathBuf::from(git(dir, &["rev-parse", "--show-prefix"])?.trim()))
+}
+
+/// Wether the repository of `dir` has a branch named `branch`.
+pub fn has_branch(dir: &Path, branch: &str) -> Result<bool, Vec<Error>> {
+    let reference = format!("refs/heads/{}", branch);
+    let refs = git(dir, &["for-each-ref", "--format=%(refname)", &reference])?;
+    Ok(refs.lines().any(|line| line == reference))
+}
+
+/// Create a new work tree at `worktree`, on a new branch starting from the
+/// current commit of the repository of `dir`.
+pub fn add_worktree(dir: &Path, branch: &str, worktree: &Path) -> Result<(), Vec<Error>> {
+    let worktree = worktree.to_string_lossy();
+    git(dir, &["worktree", "add", "--quiet", "-b", branch, &worktree, "HEAD"])?;
+    Ok(())
+}
+
+/// Remove a work tree created by [`add_worktree`].
+pub fn remove_worktree(dir: &Path, worktree: &Path) -> Result<(), Vec<Error>> {
+    let worktree = worktree.to_string_lossy();
+    git(dir, &["worktree", "remove", "--force", &worktree])?;
+    Ok(())
+}
+
+/// Delete a branch of the repository of `dir`.
+pub fn delete_branch(dir: &Path, branch: &str) -> Result<(), Vec<Error>> {
+    git(dir, &["branch", "--quiet", "-D", branch])?;
+    Ok(())
+}
+
+/// Build the message of the commit of a lock file, listing the changed inputs.
+pub fn commit_message(changes: &[InputChange]) -> String {
+    if changes.is_empty() {
+        return format!("{}: update", LOCK_FILE);
+    }
+    let names: Vec<&str> = changes.iter().map(InputChange::name).collect();
+    let mut message = format!("{}: update {}\n\n", LOCK_FILE, names.join(", "));
+    for change in changes {
+        message.push_str(&format!("- {}\n", change));
+    }
+    message
+}
@@ -0,0 +1,121 @@
+//! Ordering of the updates, so that a flake is updated after the tracked
+//! flakes it uses as inputs.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{lock::LockFile, warn, Error, Flake};
+
+/// Return the names of the given flakes in the order in which they should be updated,
+/// along with the names of the given flakes each of them depends on.
+///
+/// A flake comes after every other given flake it has as a `path:` or `git+file:`
+/// input. Among independent flakes, the ones with the highest priority come
+/// first, and ties are broken by name. Flakes sharing a path share a lock file,
+/// so each of them depends on the one before it in that order. A flake whose lock
+/// file cannot be read is warned about, and depends on no other flake.
+pub fn update_order(
+    flakes: &BTreeMap<&str, &Flake>,
+    stderr_style: bool,
+) -> Result<Vec<(String, BTreeSet<String>)>, Vec<Error>> {
+    let key = |name: &str| (Reverse(flakes[name].priority), name.to_owned());
+    let mut by_path: BTreeMap<PathBuf, Vec<&str>> = BTreeMap::new();
+    for (name, flake) in flakes {
+        by_path.entry(canonical(&flake.path)).or_default().push(name);
+    }
+    for names in by_path.values_mut() {
+        names.sort_by_key(|name| key(name));
+    }
+
+    // The tracked flakes each flake depends on.
+    let mut dependencies: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for (name, flake) in flakes {
+        let path = canonical(&flake.path);
+        let mut deps = BTreeSet::new();
+        // The update of the flake fails on its own if its lock file is broken.
+        match LockFile::read(&flake.path) {
+            Ok(Some(lock)) => {
+                for input in lock.local_paths(&flake.path) {
+                    let input = canonical(&input);
+                    if input != path {
+                        deps.extend(by_path.get(&input).into_iter().flatten());
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(errors) => {
+                for err in errors {
+                    warn(&err.msg(), stderr_style);
+                }
+                let msg = format!(
+                    "the inputs of flake `{}` are unknown, it may be updated before them",
+                    name
+                );
+                warn(&msg, stderr_style);
+            }
+        }
+        let same_path = &by_path[&path];
+        let position = same_path.iter().position(|other| other == name).unwrap();
+        if position > 0 {
+            deps.insert(same_path[position - 1]);
+        }
+        dependencies.insert(name, deps);
+    }
+
+    let mut order = Vec::with_capacity(flakes.len());
+    let mut ready: BTreeSet<_> = dependencies
+        .iter()
+        .filter(|(_, deps)| deps.is_empty())
+        .map(|(name, _)| key(name))
+        .collect();
+    let mut remaining: BTreeMap<&str, BTreeSet<&str>> = dependencies
+        .iter()
+        .filter(|(_, deps)| !deps.is_empty())
+        .map(|(name, deps)| (*name, deps.clone()))
+        .collect();
+
+    while let Some(next) = ready.pop_first() {
+        let (_, name) = next;
+        remaining.retain(|consumer, deps| {
+            deps.remove(name.as_str());
+            if deps.is_empty() {
+                ready.insert(key(consumer));
+                false
+            } else {
+                true
+            }
+        });
+        let deps = dependencies[name.as_str()]
+            .iter()
+            .map(|dep| dep.to_string())
+            .collect();
+        order.push((name, deps));
+    }
+
+    if let Some(cycle) = find_cycle(&remaining) {
+        return Err(vec![Error::Cycle(cycle)]);
+    }
+    Ok(order)
+}
+
+/// Find a cycle in a graph in which every node has at least one successor.
+fn find_cycle(graph: &BTreeMap<&str, BTreeSet<&str>>) -> Option<Vec<String>> {
+    let mut path: Vec<&str> = vec![graph.keys().next()?];
+    loop {
+        let last = path[path.len() - 1];
+        let next = graph[last].iter().next()?;
+        if let Some(start) = path.iter().position(|name| name == next) {
+            return Some(path[start..].iter().map(|name| name.to_string()).collect());
+        }
+        path.push(next);
+    }
+}
+
+/// Canonicalize a path if possible, so that several ways to write the same path compare equal.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
@@ -0,0 +1,75 @@
+//! Advisory locks, so that several SnowPlow processes can run at once.
+//!
+//! The commands changing the registry lock it from the moment they read it until
+//! they write it back, and every update or rollback of a flake locks the flake.
+//! The locks are held on files distinct from the ones they protect, since the
+//! registry is replaced rather than rewritten in place, and released when the
+//! file is closed, including when SnowPlow is killed.
+
+use std::{
+    fs::{DirBuilder, File, OpenOptions},
+    path::Path,
+};
+
+use fs4::fs_std::FileExt;
+
+use crate::{warn, Error};
+
+/// The name of the file locking the registry, inside the configuration directory.
+pub const REGISTRY_LOCK_FILE: &str = "registry.lock";
+/// The name of the directory holding the files locking the flakes, inside the state directory.
+pub const FLAKE_LOCK_DIR: &str = "locks";
+
+/// Lock the registry in `config_dir`, failing if another process holds it, unless `wait` is set.
+pub fn registry(config_dir: &Path, wait: bool, stderr_style: bool) -> Result<File, Vec<Error>> {
+    let path = config_dir.join(REGISTRY_LOCK_FILE);
+    let msg = "waiting for another snow-plow to finish changing the registry";
+    acquire(&path, wait, || warn(msg, stderr_style))?.ok_or_else(|| vec![Error::RegistryBusy])
+}
+
+/// Lock the flake at `flake_dir`, returning `None` if another process holds it, unless `wait` is set.
+/// `waiting` is called before waiting for it.
+pub fn flake(
+    state_dir: &Path,
+    flake_dir: &Path,
+    wait: bool,
+    waiting: impl FnOnce(),
+) -> Result<Option<File>, Vec<Error>> {
+    // Several entries may point to the same flake, so the lock is named after its path.
+    let name = format!("{:016x}.lock", fnv1a(flake_dir.as_os_str().as_encoded_bytes()));
+    acquire(&state_dir.join(FLAKE_LOCK_DIR).join(name), wait, waiting)
+}
+
+/// Lock the file at `path`, creating it and its directory if needed.
+fn acquire(path: &Path, wait: bool, waiting: impl FnOnce()) -> Result<Option<File>, Vec<Error>> {
+    let io_error = |e| vec![Error::Io(e, path.display().to_string())];
+    if let Some(dir) = path.parent() {
+        DirBuilder::new()
+            .recursive(true)
+            .create(dir)
+            .map_err(|e| vec![Error::Io(e, dir.display().to_string())])?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .map_err(io_error)?;
+    match file.try_lock_exclusive().map_err(io_error)? {
+        true => Ok(Some(file)),
+        false if wait => {
+            waiting();
+            file.lock_exclusive().map_err(io_error)?;
+            Ok(Some(file))
+        }
+        false => Ok(None),
+    }
+}
+
+/// The 64-bit FNV-1a hash, which unlike the hasher of the standard library
+/// does not change between versions of Rust.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
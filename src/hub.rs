@@ -0,0 +1,119 @@
+//! The hub is a flake managed by SnowPlow, whose inputs are the canonical
+//! versions of the inputs shared by the tracked flakes. It is updated first,
+//! and the matching inputs of every tracked flake are then locked to the same
+//! revision, so that all of them share one store path per input. An input is
+//! only shared with the flakes which reference the same source as the hub, so
+//! that a flake following another branch is not moved to the one of the hub.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, DirBuilder},
+    path::Path,
+};
+
+use crate::{
+    lock::{LockFile, Locked},
+    Error,
+};
+
+/// The name of the hub directory, inside the configuration directory.
+pub const HUB_DIR: &str = "hub";
+
+/// An input of the hub, as locked.
+#[derive(Clone)]
+pub struct HubInput {
+    /// The flake reference pointing exactly to its locked source.
+    pub url: String,
+    /// The source as the hub references it.
+    original: Option<Locked>,
+}
+
+/// Write the `flake.nix` of the hub in `hub_dir`, declaring the given inputs.
+/// The file is only rewritten if its content changed.
+pub fn write_flake(hub_dir: &Path, inputs: &BTreeMap<String, String>) -> Result<(), Vec<Error>> {
+    DirBuilder::new()
+        .recursive(true)
+        .create(hub_dir)
+        .map_err(|e| vec![Error::Io(e, hub_dir.display().to_string())])?;
+
+    let mut content = String::from(
+        "# This file is generated by SnowPlow, do not edit it by hand.\n\
+         {\n  \
+           description = \"Canonical inputs shared by the flakes tracked by SnowPlow\";\n  \
+           inputs = {\n",
+    );
+    for (name, url) in inputs {
+        content.push_str(&format!(
+            "    {}.url = {};\n",
+            nix_string(name),
+            nix_string(url)
+        ));
+    }
+    content.push_str("  };\n  outputs = _: { };\n}\n");
+
+    let path = hub_dir.join("flake.nix");
+    if fs::read_to_string(&path).is_ok_and(|old| old == content) {
+        return Ok(());
+    }
+    fs::write(&path, content).map_err(|e| vec![Error::Io(e, path.display().to_string())])
+}
+
+/// Read the lock file of the hub, and return each of its locked inputs.
+pub fn locked_inputs(hub_dir: &Path) -> Result<BTreeMap<String, HubInput>, Vec<Error>> {
+    let Some(lock) = LockFile::read(hub_dir)? else {
+        return Ok(BTreeMap::new());
+    };
+    Ok(lock
+        .root_nodes()
+        .filter_map(|(name, node)| {
+            let input = HubInput {
+                url: node.locked.as_ref()?.to_flake_ref()?,
+                original: node.original.clone(),
+            };
+            Some((name.to_owned(), input))
+        })
+        .collect())
+}
+
+/// Return the `--override-input` arguments locking the inputs of the flake
+/// in `flake_dir` that are also provided by the hub, and the names of the inputs
+/// of the hub which the flake references with another source, and are not locked.
+pub fn override_args(
+    hub_inputs: &BTreeMap<String, HubInput>,
+    flake_dir: &Path,
+) -> Result<(Vec<String>, Vec<String>), Vec<Error>> {
+    let Some(lock) = LockFile::read(flake_dir)? else {
+        return Ok((Vec::new(), Vec::new()));
+    };
+    let mut nodes: Vec<(&str, &HubInput, bool)> = lock
+        .root_nodes()
+        .filter_map(|(name, node)| {
+            let input = hub_inputs.get(name)?;
+            let same = input.original.is_some() && node.original == input.original;
+            Some((name, input, same))
+        })
+        .collect();
+    nodes.sort_unstable_by_key(|(name, _, _)| *name);
+
+    let mut args = Vec::new();
+    let mut skipped = Vec::new();
+    for (name, input, same) in nodes {
+        if same {
+            args.push("--override-input".to_owned());
+            args.push(name.to_owned());
+            args.push(input.url.clone());
+        } else {
+            skipped.push(name.to_owned());
+        }
+    }
+    Ok((args, skipped))
+}
+
+/// Quote a string as a nix string literal.
+fn nix_string(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${");
+    format!("\"{}\"", escaped)
+}
@@ -0,0 +1,166 @@
+//! The journal of every run of `snow-plow update`.
+//!
+//! It is an append-only file in the state directory, holding one JSON object
+//! per line, each describing a run.
+
+use std::{
+    fs::{self, DirBuilder, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    lock::{InputChange, Locked},
+    is_default, Error,
+};
+
+/// The name of the journal, inside the state directory.
+pub const JOURNAL_FILE: &str = "history.jsonl";
+
+/// A run of `snow-plow update`.
+#[derive(Serialize, Deserialize)]
+pub struct Run {
+    /// The identifier of the run, under which the lock files are backed up.
+    pub id: String,
+    /// When the run started, in seconds since the epoch.
+    pub time: i64,
+    /// The inputs which were updated, or empty if all of them were.
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    /// The arguments passed further to nix on the command line, which each
+    /// flake completes with its own.
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub flakes: Vec<FlakeRun>,
+}
+
+/// What happened to a flake during a run.
+#[derive(Serialize, Deserialize)]
+pub struct FlakeRun {
+    pub name: String,
+    pub path: PathBuf,
+    /// One of `updated`, `unchanged`, `failed` or `skipped`.
+    pub status: String,
+    /// Why the flake was skipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// How long the update took, in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    /// The arguments given to nix, with the overrides of the flake and of the hub.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// How many times the update was tried again after a transient failure.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub retries: u32,
+    /// The errors reported while updating the flake.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+    /// The changes of its direct inputs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<Change>,
+}
+
+/// The change of an input of a flake.
+#[derive(Serialize, Deserialize)]
+pub struct Change {
+    pub input: String,
+    /// The version before the update, absent if the input was added.
+    pub old: Option<Version>,
+    /// The version after the update, absent if the input was removed.
+    pub new: Option<Version>,
+}
+
+/// The version of an input.
+#[derive(Serialize, Deserialize)]
+pub struct Version {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<i64>,
+}
+
+impl From<&Locked> for Version {
+    fn from(locked: &Locked) -> Self {
+        Version {
+            url: locked.to_flake_ref(),
+            rev: locked.rev().map(str::to_owned),
+            last_modified: locked.last_modified(),
+        }
+    }
+}
+
+impl From<&InputChange> for Change {
+    fn from(change: &InputChange) -> Self {
+        let (old, new) = match change {
+            InputChange::Added(_, new) => (None, Some(new)),
+            InputChange::Removed(_, old) => (Some(old), None),
+            InputChange::Changed(_, old, new) => (Some(old), Some(new)),
+        };
+        Change {
+            input: change.name().to_owned(),
+            old: old.map(Version::from),
+            new: new.map(Version::from),
+        }
+    }
+}
+
+impl Version {
+    /// Return a short description of the version: its abbreviated revision, and its date.
+    pub fn short(&self) -> String {
+        let rev: String = self
+            .rev
+            .as_deref()
+            .unwrap_or("?")
+            .chars()
+            .take(7)
+            .collect();
+        match self.last_modified {
+            Some(time) => format!("{} ({})", rev, crate::lock::format_date(time)),
+            None => rev,
+        }
+    }
+}
+
+/// Append a run to the journal in `state_dir`.
+pub fn append(state_dir: &Path, run: &Run) -> Result<(), Vec<Error>> {
+    DirBuilder::new()
+        .recursive(true)
+        .create(state_dir)
+        .map_err(|e| vec![Error::Io(e, state_dir.display().to_string())])?;
+
+    let path = state_dir.join(JOURNAL_FILE);
+    let mut line = serde_json::to_string(run).map_err(|e| vec![Error::Internal(Box::new(e))])?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .map_err(|e| vec![Error::Io(e, path.display().to_string())])
+}
+
+/// Read every run of the journal in `state_dir`, from the oldest to the most recent.
+pub fn read(state_dir: &Path) -> Result<Vec<Run>, Vec<Error>> {
+    let path = state_dir.join(JOURNAL_FILE);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(vec![Error::Io(e, path.display().to_string())]),
+    };
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| {
+                let msg = format!("line {}: {}", i + 1, e);
+                vec![Error::Parse(path.display().to_string(), msg)]
+            })
+        })
+        .collect()
+}
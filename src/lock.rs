@@ -0,0 +1,288 @@
+//! Reading of `flake.lock` files.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use chrono::DateTime;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::Error;
+
+/// The name of the lock file of a flake.
+pub const LOCK_FILE: &str = "flake.lock";
+
+/// The content of a `flake.lock` file.
+#[derive(Deserialize)]
+pub struct LockFile {
+    pub nodes: HashMap<String, Node>,
+    pub root: String,
+}
+
+/// A node of the lock file graph.
+#[derive(Deserialize)]
+pub struct Node {
+    #[serde(default)]
+    pub inputs: HashMap<String, InputRef>,
+    /// The attributes describing the locked source, absent for the root node.
+    pub locked: Option<Locked>,
+    /// The attributes of the source as the flake references it, absent for the root node.
+    pub original: Option<Locked>,
+}
+
+/// An input of a node, which is either another node or follows a path of inputs.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum InputRef {
+    Node(String),
+    /// SnowPlow never needs to resolve the followed path.
+    #[allow(dead_code)]
+    Follows(Vec<String>),
+}
+
+/// The attributes of a locked source, as written by nix.
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct Locked(Map<String, Value>);
+
+impl LockFile {
+    /// Read the lock file of the flake in the given directory.
+    /// Return `None` if the flake has no lock file yet.
+    pub fn read(flake_dir: &Path) -> Result<Option<Self>, Vec<Error>> {
+        let path = flake_dir.join(LOCK_FILE);
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(vec![Error::Io(e, path.display().to_string())]),
+        };
+        Self::parse(&content, &path).map(Some)
+    }
+
+    /// Parse the content of the lock file at `path`.
+    pub fn parse(content: &[u8], path: &Path) -> Result<Self, Vec<Error>> {
+        serde_json::from_slice(content)
+            .map_err(|e| vec![Error::Parse(path.display().to_string(), e.to_string())])
+    }
+
+    /// Return the direct inputs of the root node which are not `follows`,
+    /// along with their locked source.
+    pub fn root_inputs(&self) -> impl Iterator<Item = (&str, &Locked)> {
+        self.root_nodes()
+            .filter_map(|(name, node)| Some((name, node.locked.as_ref()?)))
+    }
+
+    /// Return the direct inputs of the root node which are not `follows`, along with their node.
+    pub fn root_nodes(&self) -> impl Iterator<Item = (&str, &Node)> {
+        self.nodes
+            .get(&self.root)
+            .into_iter()
+            .flat_map(|root| root.inputs.iter())
+            .filter_map(|(name, input)| match input {
+                InputRef::Node(node) => Some((name.as_str(), self.nodes.get(node)?)),
+                InputRef::Follows(_) => None,
+            })
+    }
+}
+
+impl LockFile {
+    /// Return the local directories of every `path:` or `git+file:` node of the lock file.
+    /// Relative paths are resolved against `flake_dir`.
+    pub fn local_paths(&self, flake_dir: &Path) -> Vec<PathBuf> {
+        self.nodes
+            .values()
+            .filter_map(|node| node.locked.as_ref()?.local_path())
+            .map(|path| flake_dir.join(path))
+            .collect()
+    }
+}
+
+impl Locked {
+    /// Return the string attribute with the given name.
+    fn get_str(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(Value::as_str)
+    }
+
+    /// Return the revision of the source, if it has one.
+    pub fn rev(&self) -> Option<&str> {
+        self.get_str("rev")
+    }
+
+    /// Return the time of the last modification of the source, in seconds since the epoch.
+    pub fn last_modified(&self) -> Option<i64> {
+        self.0.get("lastModified").and_then(Value::as_i64)
+    }
+
+    /// Return a short description of the version of the source: its abbreviated
+    /// revision, or its abbreviated hash if it has no revision, and its date.
+    pub fn short_version(&self) -> String {
+        let id = self
+            .rev()
+            .map(|rev| rev.chars().take(7).collect())
+            .or_else(|| {
+                let hash = self.get_str("narHash")?;
+                let hash = hash.strip_prefix("sha256-").unwrap_or(hash);
+                Some(hash.chars().take(7).collect::<String>())
+            })
+            .unwrap_or_else(|| "?".to_owned());
+        match self.last_modified() {
+            Some(time) => format!("{} ({})", id, format_date(time)),
+            None => id,
+        }
+    }
+
+    /// Return the directory of a local source, if it is one.
+    fn local_path(&self) -> Option<PathBuf> {
+        let mut path = match self.get_str("type")? {
+            "path" => PathBuf::from(self.get_str("path")?),
+            "git" => PathBuf::from(self.get_str("url")?.strip_prefix("file://")?),
+            _ => return None,
+        };
+        if let Some(dir) = self.get_str("dir") {
+            path.push(dir);
+        }
+        Some(path)
+    }
+
+    /// Build a flake reference pointing exactly to this locked source,
+    /// suitable for `--override-input`.
+    pub fn to_flake_ref(&self) -> Option<String> {
+        let mut query = Vec::new();
+        if let Some(nar_hash) = self.get_str("narHash") {
+            query.push(format!("narHash={}", nar_hash));
+        }
+        let base = match self.get_str("type")? {
+            kind @ ("github" | "gitlab" | "sourcehut") => {
+                if let Some(host) = self.get_str("host") {
+                    query.push(format!("host={}", host));
+                }
+                format!(
+                    "{}:{}/{}/{}",
+                    kind,
+                    self.get_str("owner")?,
+                    self.get_str("repo")?,
+                    self.get_str("rev")?,
+                )
+            }
+            kind @ ("git" | "hg") => {
+                if let Some(reference) = self.get_str("ref") {
+                    query.push(format!("ref={}", reference));
+                }
+                query.push(format!("rev={}", self.get_str("rev")?));
+                format!("{}+{}", kind, self.get_str("url")?)
+            }
+            "path" => format!("path:{}", self.get_str("path")?),
+            kind @ ("tarball" | "file") => format!("{}+{}", kind, self.get_str("url")?),
+            _ => return None,
+        };
+
+        if query.is_empty() {
+            Some(base)
+        } else {
+            let separator = if base.contains('?') { '&' } else { '?' };
+            Some(format!("{}{}{}", base, separator, query.join("&")))
+        }
+    }
+}
+
+/// Write the lock file of the flake in `flake_dir`, or remove it if there is no content.
+pub fn write(flake_dir: &Path, content: Option<&[u8]>) -> Result<(), Vec<Error>> {
+    let path = flake_dir.join(LOCK_FILE);
+    let result = match content {
+        Some(content) => {
+            let tmp_path = path.with_extension("tmp");
+            fs::write(&tmp_path, content).and_then(|()| fs::rename(&tmp_path, &path))
+        }
+        None => match fs::remove_file(&path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        },
+    };
+    result.map_err(|e| vec![Error::Io(e, path.display().to_string())])
+}
+
+/// A change of a direct input between two versions of a lock file.
+pub enum InputChange {
+    Added(String, Locked),
+    Removed(String, Locked),
+    Changed(String, Locked, Locked),
+}
+
+impl InputChange {
+    /// Return the name of the changed input.
+    pub fn name(&self) -> &str {
+        match self {
+            InputChange::Added(name, _)
+            | InputChange::Removed(name, _)
+            | InputChange::Changed(name, _, _) => name,
+        }
+    }
+}
+
+impl fmt::Display for InputChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InputChange::Added(name, new) => {
+                write!(f, "{}: added {}", name, new.short_version())
+            }
+            InputChange::Removed(name, old) => {
+                write!(f, "{}: removed {}", name, old.short_version())
+            }
+            InputChange::Changed(name, old, new) => {
+                write!(
+                    f,
+                    "{}: {} -> {}",
+                    name,
+                    old.short_version(),
+                    new.short_version()
+                )?;
+                if let (Some(old), Some(new)) = (old.last_modified(), new.last_modified()) {
+                    let days = (new - old) / (24 * 60 * 60);
+                    if days == 1 {
+                        write!(f, ", 1 day")?;
+                    } else {
+                        write!(f, ", {} days", days)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Compare the direct inputs of two versions of a lock file, sorted by name.
+pub fn diff(old: Option<&LockFile>, new: Option<&LockFile>) -> Vec<InputChange> {
+    let inputs = |lock: Option<&LockFile>| -> BTreeMap<String, Locked> {
+        lock.into_iter()
+            .flat_map(LockFile::root_inputs)
+            .map(|(name, locked)| (name.to_owned(), locked.clone()))
+            .collect()
+    };
+    let mut old = inputs(old);
+    let new = inputs(new);
+
+    let mut changes = Vec::new();
+    for (name, new) in new {
+        match old.remove(&name) {
+            None => changes.push(InputChange::Added(name, new)),
+            Some(old) if old != new => changes.push(InputChange::Changed(name, old, new)),
+            Some(_) => {}
+        }
+    }
+    changes.extend(
+        old.into_iter()
+            .map(|(name, old)| InputChange::Removed(name, old)),
+    );
+    changes.sort_by(|a, b| a.name().cmp(b.name()));
+    changes
+}
+
+/// Format a time in seconds since the epoch as a date.
+pub fn format_date(time: i64) -> String {
+    match DateTime::from_timestamp(time, 0) {
+        Some(date) => date.format("%Y-%m-%d").to_string(),
+        None => time.to_string(),
+    }
+}
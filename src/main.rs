@@ -4,185 +4,464 @@
 
 use std::{
     borrow::Cow,
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet},
     env,
     error::Error as ErrorTrait,
     fmt,
-    fs::{self, DirBuilder, File},
-    io::{self, BufRead, Error as IoError, IsTerminal},
+    fs::{DirBuilder, File},
+    io::{self, Error as IoError, IsTerminal},
+    num::NonZeroUsize,
     path::{self, Path, PathBuf},
-    process::{self, Command},
+    process,
+    time::Duration,
 };
 
 use ansi_term::{ANSIGenericString, Colour, Style};
-use clap::{Args, ColorChoice, Command as ClapCommand, CommandFactory, Parser, Subcommand};
+use chrono::{DateTime, Local, NaiveDate};
+use clap::{
+    Args, ColorChoice, Command as ClapCommand, CommandFactory, Parser, Subcommand, ValueEnum,
+};
 use clap_complete::{generate_to, Shell};
 use clap_mangen::Man;
 use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
 
-const CONFIG_FILE: &str = "config.csv";
+mod backend;
+mod backup;
+mod diagnostic;
+mod edit;
+#[cfg(debug_assertions)]
+mod fake;
+mod git;
+mod graph;
+mod guard;
+mod hub;
+mod journal;
+mod lock;
+mod nix;
+mod registry;
+mod signal;
+mod transfer;
+mod update;
+
+use backend::{Backend, Control, Output};
+use backup::Backups;
+use diagnostic::{Diagnostic, Message};
+#[cfg(debug_assertions)]
+use fake::FakeBackend;
+use hub::HubInput;
+use nix::NixBackend;
+use registry::{NamedFlake, NixSettings, Registry, RetryPolicy, Settings, LEGACY_CONFIG_FILE, REGISTRY_FILE};
+use transfer::{Conflict, Format};
 
-/// Used for serializing flakes.
-#[derive(Serialize, Deserialize)]
-struct NamedFlake {
-    name: String,
-    path: PathBuf,
-    enabled: bool,
+/// Represents a flake managed by SnowPlow.
+/// If it is not enabled, it will not be updated.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Flake {
+    /// The absolute path of the flake directory.
+    pub path: PathBuf,
+    pub enabled: bool,
+    /// Among flakes which do not depend on each other, the ones with the highest
+    /// priority are updated first.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub priority: i64,
+    /// The steps run after each update. If one of them fails, the lock file is restored.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub verify: Vec<Verification>,
+    /// Commit the lock file after each update, as with `update --commit`.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub commit: bool,
+    /// How long the update of the flake may last, in seconds, instead of `update --timeout`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// The tags by which the flake can be selected, such as `work` or `server`.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub tags: BTreeSet<String>,
+    /// The arguments given to nix when updating the flake, before the ones given on the command line.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// The inputs always overridden when updating the flake, and their flake reference.
+    /// They take precedence over the inputs of the hub.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub overrides: BTreeMap<String, String>,
 }
 
-impl From<NamedFlake> for (String, Flake) {
-    fn from(named_flake: NamedFlake) -> (String, Flake) {
-        let flake = Flake {
-            path: named_flake.path,
-            enabled: named_flake.enabled,
-        };
-        (named_flake.name, flake)
+impl Flake {
+    /// Return the arguments given to nix when updating the flake: its overridden
+    /// inputs, its own arguments, then the given ones from the command line.
+    pub fn nix_args(&self, args: &[String]) -> Vec<String> {
+        let mut nix_args = Vec::new();
+        for (input, url) in &self.overrides {
+            nix_args.extend(["--override-input".to_owned(), input.clone(), url.clone()]);
+        }
+        nix_args.extend_from_slice(&self.args);
+        nix_args.extend_from_slice(args);
+        nix_args
     }
 }
 
-impl From<(String, Flake)> for NamedFlake {
-    fn from((name, flake): (String, Flake)) -> Self {
-        NamedFlake {
-            name,
-            path: flake.path,
-            enabled: flake.enabled,
-        }
-    }
+/// A step checking that a flake still works after an update.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verification {
+    /// Run `nix flake check`.
+    Check,
+    /// Run `nix eval` on the given attribute.
+    Eval(String),
+    /// Run `nix build` on the given output.
+    Build(String),
 }
 
-/// Represents a flake managed by SnowPlow.
-/// If it is not enabled, it will not be updated.
-#[derive(Clone)]
-struct Flake {
-    /// The absolute path of the flake directory.
-    path: PathBuf,
-    enabled: bool,
+/// How thoroughly a flake is validated when it is added or checked.
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Validation {
+    /// Do not validate the flake.
+    None,
+    /// Check that the flake has a `flake.nix`, and that its lock file, if any, can be parsed.
+    Syntax,
+    /// Also run `nix flake metadata`, which fetches the flake without evaluating its outputs.
+    #[default]
+    Metadata,
+    /// Also run `nix flake show`, which evaluates all of its outputs.
+    Show,
+}
+
+/// How a command uses the registry and the flakes, which other SnowPlow processes may be using too.
+#[derive(Clone, Copy)]
+struct Access {
+    /// Wether the command changes the registry, which is then locked until it is written back.
+    /// Otherwise, the registry is not written back.
+    write: bool,
+    /// Wait for the other processes to release the registry or a flake, instead of failing.
+    wait: bool,
 }
 
 /// The main interface of the software.
 struct Interface {
-    /// The path to the config file.
+    /// The path to the registry file.
     config_path: PathBuf,
-    flakes: HashMap<String, Flake>,
+    /// The directory where the backups and the journal are kept.
+    state_dir: PathBuf,
+    settings: Settings,
+    flakes: BTreeMap<String, Flake>,
+    /// The inputs of the hub, by name, and their flake reference.
+    hub: BTreeMap<String, String>,
+    /// The way nix is run.
+    backend: Box<dyn Backend>,
+    /// Print everything nix prints.
+    verbose: bool,
     /// Control wether ANSI escape code are used or not to format the ouput.
     stdout_style: bool,
     /// Control wether ANSI escape code are used or not to format the ouput.
     stderr_style: bool,
     /// Record wether the data has been properly saved.
     cleaned: bool,
+    access: Access,
+    /// The lock on the registry, held until SnowPlow exits if it changes the registry.
+    registry_lock: Option<File>,
 }
 
-enum Error {
+pub enum Error {
     /// IO errors, and the file in which it occurs.
     Io(IoError, String),
+    /// Parsing errors, and the file in which it occurs.
+    Parse(String, String),
+    /// When the registry has been written by a newer version of SnowPlow.
+    RegistryVersion(u32),
     /// Errors reported by nix.
-    Nix(Vec<String>),
+    Nix(Diagnostic),
     /// When no configuration directory was found.
     NoConfig,
+    /// When no state directory was found.
+    NoState,
     /// When adding a flake when there is already a tracked flake with the same name.  
     TrackedFlake(String),
+    /// When importing settings which differ from the current ones.
+    ConflictingSettings,
+    /// When importing a hub input which already points to the given flake reference.
+    ConflictingHubInput(String, String),
     /// When removing a flake that is not tracked.
     MissingFlake(String),
     /// When updating a flake which is not tracked.
     NoFlake(String),
+    /// When the directory of a flake has no `flake.nix`.
+    NotAFlake(String),
+    /// When some flakes are not valid, and how many.
+    Invalid(usize),
+    /// When another process is changing the registry.
+    RegistryBusy,
+    /// When the editor of the registry failed, or exited with an error.
+    Editor(String),
+    /// When another process is updating the given flake.
+    FlakeBusy(String),
+    /// When the installed nix is too old, or its version cannot be read.
+    UnsupportedNix(String),
+    /// When there is no backup to roll back, of the given flake if any.
+    NoBackup(Option<String>),
+    /// Errors reported by git.
+    Git(String),
+    /// When the lock file of a flake to commit has uncommitted changes before the update.
+    DirtyLock(String),
+    /// When tracked flakes depend on each other in a cycle.
+    Cycle(Vec<String>),
+    /// When some flakes failed to be updated, and how many.
+    Failed(usize),
+    /// When nix has been killed because it ran for longer than the given timeout.
+    Timeout(Duration),
+    /// When SnowPlow has been interrupted by a signal.
+    Interrupted,
     /// An internal error occured.
-    Internal(Box<dyn ErrorTrait>),
+    Internal(Box<dyn ErrorTrait + Send + Sync>),
 }
 
 impl Error {
     fn msg(&self) -> String {
         match self {
             Error::Io(e, file) => format!("{}: {}", file, e),
-            Error::Nix(errors) => {
-                let mut errors = errors.iter();
-                let Some(mut s) = errors.next().cloned() else {
-                    return String::new();
-                };
-
-                for e in errors {
-                    s.push_str("\n");
-                    s.push_str(e);
-                }
-                s
-            }
+            Error::Parse(file, e) => format!("{}: {}", file, e),
+            Error::RegistryVersion(version) => format!(
+                "the registry uses version {} of the format, which is not supported by this version of SnowPlow",
+                version
+            ),
+            Error::Nix(diagnostic) => format!("{} error: {}", diagnostic.kind, diagnostic),
             Error::NoConfig => {
                 "no user provided configuration and unable to find the system default location"
                     .to_owned()
             }
+            Error::NoState => {
+                "no user provided state directory and unable to find the system default location"
+                    .to_owned()
+            }
             Error::TrackedFlake(name) => format!("flake `{}` is already tracked", name),
+            Error::ConflictingSettings => "the settings differ from the imported ones".to_owned(),
+            Error::ConflictingHubInput(input, url) => {
+                format!("hub input `{}` already points to `{}`", input, url)
+            }
             Error::MissingFlake(name) => format!("flake `{}` is not tracked", name),
             Error::NoFlake(name) => format!("no flake named `{}`", name),
+            Error::UnsupportedNix(version) => format!(
+                "unsupported nix version `{}`, SnowPlow needs Nix 2.4 or later",
+                version
+            ),
+            Error::NoBackup(None) => "no backup to roll back".to_owned(),
+            Error::NoBackup(Some(name)) => format!("no backup of flake `{}`", name),
+            Error::Git(e) => format!("git: {}", e),
+            Error::DirtyLock(name) => format!(
+                "the lock file of flake `{}` has uncommitted changes, refusing to commit it",
+                name
+            ),
+            Error::Cycle(names) => {
+                let mut cycle: Vec<String> = names.iter().map(|name| format!("`{}`", name)).collect();
+                cycle.push(cycle[0].clone());
+                format!(
+                    "tracked flakes depend on each other in a cycle: {}",
+                    cycle.join(" -> ")
+                )
+            }
+            Error::NotAFlake(path) => format!("`{}` is not a flake, it has no `flake.nix`", path),
+            Error::Editor(editor) => {
+                format!("the editor `{}` failed, the registry has not been changed", editor)
+            }
+            Error::RegistryBusy => {
+                "another snow-plow is changing the registry, retry later or use `--wait`".to_owned()
+            }
+            Error::FlakeBusy(name) => {
+                format!("flake `{}` is being updated by another snow-plow", name)
+            }
+            Error::Invalid(1) => "1 flake is not valid".to_owned(),
+            Error::Invalid(nb) => format!("{} flakes are not valid", nb),
+            Error::Failed(1) => "1 flake failed to be updated".to_owned(),
+            Error::Failed(nb) => format!("{} flakes failed to be updated", nb),
+            Error::Timeout(timeout) => format!("timed out after {}s", timeout.as_secs()),
+            Error::Interrupted => "interrupted".to_owned(),
             Error::Internal(e) => format!("internal: {}", e),
         }
     }
+
+    /// Wether trying again later may succeed.
+    fn is_transient(&self) -> bool {
+        matches!(self, Error::Nix(diagnostic) if diagnostic.is_transient())
+    }
 }
 
 /// Public interface
 impl Interface {
-    /// Create a new `Interface`. It reads the configuration from `config_dir/CONFIG_FILE`,
-    /// and creates it if necessary.
-    fn new(config_dir: PathBuf, stdout_style: bool, stderr_style: bool) -> Self {
-        let flakes = HashMap::new();
-
+    /// Create a new `Interface`. It reads the registry from `config_dir/REGISTRY_FILE`,
+    /// and creates it if necessary, migrating the legacy `config.csv` if there is one.
+    /// The backend running nix is built from the settings of the registry.
+    fn new(
+        config_dir: PathBuf,
+        state_dir: PathBuf,
+        access: Access,
+        backend: impl FnOnce(&Settings) -> Result<Box<dyn Backend>, Vec<Error>>,
+        verbose: bool,
+        stdout_style: bool,
+        stderr_style: bool,
+    ) -> Self {
         let mut config_path = config_dir.to_owned();
-        config_path.push(CONFIG_FILE);
+        config_path.push(REGISTRY_FILE);
+
+        // The registry is locked before being read, so that the changes of another process are not lost.
+        let registry_lock = access
+            .write
+            .then(|| guard::registry(&config_dir, access.wait, stderr_style))
+            .transpose();
+        let (registry, backend, registry_lock) = match registry_lock.and_then(|registry_lock| {
+            let registry = Self::init(&config_dir, &config_path, stderr_style)?;
+            Ok((backend(&registry.settings)?, registry, registry_lock))
+        }) {
+            Ok((backend, registry, registry_lock)) => (registry, backend, registry_lock),
+            Err(e) => {
+                Self::handle_errors(e, true, stderr_style);
+                unreachable!();
+            }
+        };
 
         let mut this = Interface {
             config_path,
-            flakes,
+            state_dir,
+            settings: registry.settings,
+            flakes: BTreeMap::new(),
+            hub: registry.hub,
+            backend,
+            verbose,
             stdout_style,
             stderr_style,
             cleaned: false,
+            access,
+            registry_lock,
         };
-
-        if let Err(e) = this.init(config_dir) {
-            Self::handle_errors(e, true, this.stderr_style);
+        for named_flake in registry.flakes {
+            let (name, flake) = named_flake.into();
+            if let Some(old_flake) = this.flakes.insert(name.clone(), flake) {
+                let msg = format!(
+                    "flake `{}` is present several time in the file. \"{}\" has been removed.",
+                    name,
+                    old_flake.path.display(),
+                );
+                warn(&msg, this.stderr_style);
+            }
         }
 
         this
     }
 
-    fn add_flake(&mut self, name: String, path: PathBuf) -> Result<(), Vec<Error>> {
+    fn add_flake(
+        &mut self,
+        name: String,
+        path: PathBuf,
+        validation: Option<Validation>,
+    ) -> Result<(), Vec<Error>> {
         if self.flakes.contains_key(&name) {
             return Err(vec![Error::TrackedFlake(name)]);
         }
-        self.check_flake(&path)?;
+        self.check_flake(&name, &path, validation.unwrap_or(self.settings.validation))?;
         let flake = Flake {
             path: path::absolute(&path)
                 .map_err(|e| vec![Error::Io(e, path.display().to_string())])?,
             enabled: true,
+            ..Flake::default()
         };
         self.flakes.insert(name, flake);
 
         Ok(())
     }
 
-    fn enable_flake(&mut self, name: String) -> Result<(), Vec<Error>> {
-        let flake = self.get_flake_mut(&name)?;
+    fn enable_flake(&mut self, name: Option<String>, tags: TagFilter) -> Result<(), Vec<Error>> {
+        for name in self.select_flakes(name, &tags)? {
+            let flake = self.get_flake_mut(&name)?;
 
-        let should_warn = flake.enabled;
-        flake.enabled = true;
+            let should_warn = flake.enabled;
+            flake.enabled = true;
 
-        if should_warn {
-            let msg = format!("flake `{}` is already enabled", name);
-            warn(&msg, self.stderr_style);
+            if should_warn {
+                let msg = format!("flake `{}` is already enabled", name);
+                warn(&msg, self.stderr_style);
+            }
         }
 
         Ok(())
     }
 
-    fn disable_flake(&mut self, name: String) -> Result<(), Vec<Error>> {
+    fn disable_flake(&mut self, name: Option<String>, tags: TagFilter) -> Result<(), Vec<Error>> {
+        for name in self.select_flakes(name, &tags)? {
+            let flake = self.get_flake_mut(&name)?;
+
+            let should_warn = !flake.enabled;
+            flake.enabled = false;
+
+            if should_warn {
+                let msg = format!("flake `{}` is already disabled", name);
+                warn(&msg, self.stderr_style);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_flake(&mut self, name: String, options: SetOptions) -> Result<(), Vec<Error>> {
+        let SetOptions {
+            args,
+            clear_args,
+            override_input,
+            remove_override,
+        } = options;
+        let stderr_style = self.stderr_style;
         let flake = self.get_flake_mut(&name)?;
 
-        let should_warn = !flake.enabled;
-        flake.enabled = false;
+        if clear_args {
+            flake.args.clear();
+        }
+        flake.args.extend(args);
+        for input in remove_override {
+            if flake.overrides.remove(&input).is_none() {
+                let msg = format!("input `{}` of flake `{}` is not overridden", input, name);
+                warn(&msg, stderr_style);
+            }
+        }
+        for pair in override_input.chunks_exact(2) {
+            flake.overrides.insert(pair[0].clone(), pair[1].clone());
+        }
+        Ok(())
+    }
 
-        if should_warn {
-            let msg = format!("flake `{}` is already disabled", name);
-            warn(&msg, self.stderr_style);
+    fn add_tag(&mut self, tag: String, names: Vec<String>) -> Result<(), Vec<Error>> {
+        for name in names {
+            let flake = self.get_flake_mut(&name)?;
+            if !flake.tags.insert(tag.clone()) {
+                let msg = format!("flake `{}` is already tagged `{}`", name, tag);
+                warn(&msg, self.stderr_style);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_tag(&mut self, tag: String, names: Vec<String>) -> Result<(), Vec<Error>> {
+        for name in names {
+            let flake = self.get_flake_mut(&name)?;
+            if !flake.tags.remove(&tag) {
+                let msg = format!("flake `{}` is not tagged `{}`", name, tag);
+                warn(&msg, self.stderr_style);
+            }
         }
+        Ok(())
+    }
 
+    fn list_tags(&self) -> Result<(), Vec<Error>> {
+        let mut tags: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (name, flake) in &self.flakes {
+            for tag in &flake.tags {
+                tags.entry(tag).or_default().push(name);
+            }
+        }
+        for (tag, names) in tags {
+            println!(
+                "{} {}",
+                apply_style(Style::new().bold(), tag, self.stdout_style),
+                names.join(" ")
+            );
+        }
         Ok(())
     }
 
@@ -195,57 +474,166 @@ impl Interface {
         Ok(())
     }
 
-    fn update_flakes(&self, name : Option<String>, args: Vec<String>) -> Result<(), Vec<Error>> {
-        if let Some(name) = name {
-            let Some((name, flake)) = self.flakes.iter().find(|(n, _)| *n == &name)
-            else {
-                Self::handle_errors(vec![Error::NoFlake(name)], true, self.stderr_style);
-                unreachable!();
-            };
+    fn rename_flake(&mut self, old_name: String, new_name: String) -> Result<(), Vec<Error>> {
+        if self.flakes.contains_key(&new_name) {
+            return Err(vec![Error::TrackedFlake(new_name)]);
+        }
+        // The flake is locked so that a running update does not back it up under the old name.
+        let _lock = self.lock_flake(&old_name, &self.get_flake(&old_name)?.path)?;
+        Backups::new(&self.state_dir).rename(&old_name, &new_name)?;
+        let flake = self.flakes.remove(&old_name).unwrap();
+        self.flakes.insert(new_name, flake);
 
-            if flake.enabled {
-                println!(
-                    "updating flake `{}` at \"{}\"",
-                    name,
-                    flake.path.display(),
-                );
-                if let Err(errors) = self.update_flake(&flake.path, &args) {
-                    Self::handle_errors(errors, true, self.stderr_style);
+        Ok(())
+    }
+
+    fn move_flake(&mut self, name: String, path: PathBuf) -> Result<(), Vec<Error>> {
+        self.get_flake(&name)?;
+        self.check_flake(&name, &path, self.settings.validation)?;
+        let path =
+            path::absolute(&path).map_err(|e| vec![Error::Io(e, path.display().to_string())])?;
+        self.get_flake_mut(&name)?.path = path;
+
+        Ok(())
+    }
+
+    fn rollback(&self, name: Option<String>, run: Option<String>) -> Result<(), Vec<Error>> {
+        let backups = Backups::new(&self.state_dir);
+        let runs = backups.runs()?;
+        let run = match (run, &name) {
+            (Some(run), _) if runs.contains(&run) => run,
+            (Some(_), _) => return Err(vec![Error::NoBackup(name)]),
+            (None, None) => runs.last().cloned().ok_or(vec![Error::NoBackup(None)])?,
+            (None, Some(name)) => {
+                let mut found = None;
+                for run in runs.iter().rev() {
+                    if backups.flakes(run)?.contains(name) {
+                        found = Some(run.clone());
+                        break;
+                    }
                 }
+                found.ok_or_else(|| vec![Error::NoBackup(Some(name.clone()))])?
             }
+        };
 
-            Ok(())
-        } else {
-        let nb = self
-            .flakes
-            .iter()
-            .filter(|(_, flake)| flake.enabled)
-            .count();
-        for (i, (name, flake)) in self.flakes.iter().enumerate() {
-            if flake.enabled {
-                println!(
-                    "updating flake `{}` at \"{}\" {}/{}",
-                    name,
-                    flake.path.display(),
-                    i,
-                    nb,
-                );
-                if let Err(errors) = self.update_flake(&flake.path, &args) {
-                    // We do not exit because some flake may fail to be updated while another do not.
-                    Self::handle_errors(errors, false, self.stderr_style);
+        let names = match name {
+            Some(name) => vec![name],
+            None => backups.flakes(&run)?,
+        };
+        for name in names {
+            let flake = self.get_flake(&name)?;
+            let _lock = self.lock_flake(&name, &flake.path)?;
+            if !backups.restore(&run, &name, &flake.path)? {
+                return Err(vec![Error::NoBackup(Some(name))]);
+            }
+            println!(
+                "restored the lock file of flake `{}` from run {}",
+                name, run
+            );
+        }
+        Ok(())
+    }
+
+    fn show_history(
+        &self,
+        name: Option<String>,
+        since: Option<NaiveDate>,
+        until: Option<NaiveDate>,
+    ) -> Result<(), Vec<Error>> {
+        for run in journal::read(&self.state_dir)? {
+            let Some(time) = DateTime::from_timestamp(run.time, 0) else {
+                continue;
+            };
+            let time = time.with_timezone(&Local);
+            let date = time.date_naive();
+            if since.is_some_and(|since| date < since) || until.is_some_and(|until| date > until)
+            {
+                continue;
+            }
+            let flakes: Vec<&journal::FlakeRun> = run
+                .flakes
+                .iter()
+                .filter(|flake| name.as_ref().is_none_or(|name| &flake.name == name))
+                .collect();
+            if flakes.is_empty() {
+                continue;
+            }
+
+            let mut header = format!("run {} on {}", run.id, time.format("%Y-%m-%d %H:%M:%S"));
+            if !run.inputs.is_empty() {
+                header.push_str(&format!(", inputs {}", run.inputs.join(" ")));
+            }
+            if !run.args.is_empty() {
+                header.push_str(&format!(", nix arguments {}", run.args.join(" ")));
+            }
+            println!("{}", apply_style(Style::new().bold(), header, self.stdout_style));
+            for flake in flakes {
+                match &flake.reason {
+                    Some(reason) => println!("  {} {} ({})", flake.name, flake.status, reason),
+                    None => println!("  {} {}", flake.name, flake.status),
+                }
+                for change in &flake.changes {
+                    match (&change.old, &change.new) {
+                        (Some(old), Some(new)) => {
+                            println!("    {}: {} -> {}", change.input, old.short(), new.short())
+                        }
+                        (None, Some(new)) => println!("    {}: added {}", change.input, new.short()),
+                        (Some(old), None) => {
+                            println!("    {}: removed {}", change.input, old.short())
+                        }
+                        (None, None) => {}
+                    }
+                }
+                for error in &flake.errors {
+                    for line in error.lines() {
+                        println!("    {}", line);
+                    }
                 }
             }
         }
         Ok(())
     }
+
+    fn add_hub_input(&mut self, name: String, url: String) -> Result<(), Vec<Error>> {
+        if let Some(old_url) = self.hub.insert(name.clone(), url) {
+            let msg = format!("hub input `{}` was pointing to `{}`", name, old_url);
+            warn(&msg, self.stderr_style);
+        }
+        Ok(())
+    }
+
+    fn remove_hub_input(&mut self, name: String) -> Result<(), Vec<Error>> {
+        if self.hub.remove(&name).is_none() {
+            let msg = format!("hub input `{}` does not exists", name);
+            warn(&msg, self.stderr_style);
+        }
+        Ok(())
+    }
+
+    fn list_hub_inputs(&self) -> Result<(), Vec<Error>> {
+        let locked = hub::locked_inputs(&self.hub_dir())?;
+        for (name, url) in &self.hub {
+            let locked = locked
+                .get(name)
+                .map(|input| input.url.as_str())
+                .unwrap_or("not locked");
+            println!(
+                "{} {} {}",
+                apply_style(Style::new().bold(), name, self.stdout_style),
+                url,
+                locked,
+            );
+        }
+        Ok(())
     }
 
-    fn list_flakes(&self, filter: ListFilter) -> Result<(), Vec<Error>> {
+    fn list_flakes(&self, filter: ListFilter, tags: TagFilter) -> Result<(), Vec<Error>> {
         let some_filter = filter.enabled || filter.disabled;
         for (name, flake) in self.flakes.iter() {
-            let selected = !some_filter
+            let selected = (!some_filter
                 || (filter.enabled && flake.enabled)
-                || (filter.disabled && !flake.enabled);
+                || (filter.disabled && !flake.enabled))
+                && tags.matches(flake);
             if selected {
                 let info = if !some_filter {
                     if flake.enabled {
@@ -267,14 +655,45 @@ impl Interface {
         Ok(())
     }
 
-    fn info_flake(&self, name: String) -> Result<(), Vec<Error>> {
-        let flake = self.get_flake(&name)?;
-        println!(
-            "{} {} {}",
-            apply_style(Style::new().bold(), &name, self.stdout_style),
-            flake.path.display(),
-            if flake.enabled { "enabled" } else { "disabled" }
-        );
+    fn info_flake(&self, name: Option<String>, tags: TagFilter, metadata: bool) -> Result<(), Vec<Error>> {
+        for name in self.select_flakes(name, &tags)? {
+            let flake = self.get_flake(&name)?;
+            println!(
+                "{} {} {}",
+                apply_style(Style::new().bold(), &name, self.stdout_style),
+                flake.path.display(),
+                if flake.enabled { "enabled" } else { "disabled" }
+            );
+            if !flake.tags.is_empty() {
+                let tags: Vec<&str> = flake.tags.iter().map(String::as_str).collect();
+                println!("tags: {}", tags.join(" "));
+            }
+            if !flake.args.is_empty() {
+                println!("nix arguments: {}", flake.args.join(" "));
+            }
+            for (input, url) in &flake.overrides {
+                println!("input `{}` overridden by `{}`", input, url);
+            }
+            if !metadata {
+                continue;
+            }
+            // The flake may be broken, which should not prevent showing where it is.
+            match self.flake_metadata(&name, &flake.path) {
+                Ok(metadata) => {
+                    if let Some(description) = metadata["description"].as_str() {
+                        println!("description: {}", description);
+                    }
+                    if let Some(time) = metadata["lastModified"].as_i64() {
+                        println!("last modified: {}", lock::format_date(time));
+                    }
+                }
+                Err(errors) => {
+                    for e in errors {
+                        warn(&e.msg(), self.stderr_style);
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
@@ -305,7 +724,9 @@ impl Interface {
             error(&err.msg(), stderr_style);
             if should_exit {
                 let error_code = match err {
-                    Error::Io(e, _) => e.kind() as i32,
+                    // Not 2, which is the status of the usage errors reported by clap.
+                    Error::Failed(_) | Error::Invalid(_) => 3,
+                    Error::Interrupted => signal::EXIT_CODE,
                     _ => 1,
                 };
                 process::exit(error_code);
@@ -313,77 +734,249 @@ impl Interface {
         }
     }
 
+    /// Return the registry as it is in memory.
+    fn registry(&self) -> Registry {
+        let mut flakes: Vec<NamedFlake> = self
+            .flakes
+            .iter()
+            .map(|(name, flake)| NamedFlake::from((name.clone(), flake.clone())))
+            .collect();
+        flakes.sort_by(|a, b| a.name.cmp(&b.name));
+        Registry {
+            settings: self.settings.clone(),
+            hub: self.hub.clone(),
+            flakes,
+            ..Registry::default()
+        }
+    }
+
     /// Save the data and exits properly. It should never return Ok(()).
     fn clean(&mut self) -> Result<(), Vec<Error>> {
         // TODO: When <https://github.com/rust-lang/rust/issues/35121> is stabilized, we can replace () by !
-        let tmp_path = self.config_path.with_extension("tmp");
-        let file = File::create(&tmp_path)
-            .map_err(|e| vec![Error::Io(e, tmp_path.display().to_string())])?;
-        let mut writer = csv::Writer::from_writer(file);
-        for (name, flake) in &self.flakes {
-            let named_flake = NamedFlake::from((name.clone(), flake.clone()));
-            writer
-                .serialize(named_flake)
-                .map_err(|e| vec![Error::Internal(Box::new(e))])?;
+        if !self.access.write {
+            // Another process may have changed the registry in the meantime.
+            self.cleaned = true;
+            return Ok(());
         }
-        fs::rename(&tmp_path, &self.config_path)
-            .map_err(|e| vec![Error::Io(e, tmp_path.display().to_string())])?;
+        self.registry().save(&self.config_path)?;
         self.cleaned = true;
+        self.registry_lock = None;
         Ok(())
     }
 }
 
 /// Private functions
 impl Interface {
-    /// Wrap a Command and build error messages
-    fn perform(&self, cmd: &mut Command) -> Result<(), Vec<Error>> {
-        let output = cmd
-            .output()
-            .map_err(|e| vec![Error::Io(e, "shell".into())])?;
-        if !output.status.success() {
-            let mut v = Vec::new();
-            // append every line following an `error:` to the current error,
-            // until another error or warning starts
-            let mut current_error = None;
-            let mut push_error = |error: &mut Option<Vec<String>>| {
-                if let Some(err) = error.take() {
-                    v.push(Error::Nix(err));
+    /// Build error messages from the output of nix, and return its standard output if it succeeded.
+    fn perform(&self, output: Output, log: &mut Log) -> Result<Vec<u8>, Vec<Error>> {
+        if output.success {
+            return Ok(output.stdout);
+        }
+
+        let mut errors = Vec::new();
+        for message in diagnostic::parse(&output.stderr) {
+            match message {
+                Message::Error(diagnostic) => errors.push(Error::Nix(diagnostic)),
+                Message::Warning(diagnostic) => {
+                    log.warn(format!("nix: {}", diagnostic), self.stderr_style)
                 }
-            };
-            for line in output.stderr.lines() {
-                let line = line.map_err(|e| vec![Error::Io(e, "nix".into())])?;
-                if line.starts_with("error:") {
-                    push_error(&mut current_error);
-                    current_error = Some(vec![line.trim().to_owned()]);
-                } else if line.starts_with("warning:") {
-                    push_error(&mut current_error);
-                    warn(&format!("nix: {}", line.trim()), self.stderr_style);
-                } else {
-                    match current_error.as_mut() {
-                        Some(err) => {
-                            err.push(line.trim().to_owned());
-                        }
-                        None => warn(&format!("nix: {}", line.trim()), self.stderr_style),
+            }
+        }
+        if errors.is_empty() {
+            let message = "nix failed without reporting any error".to_owned();
+            errors.push(Error::Nix(Diagnostic::new(message, None, Vec::new())));
+        }
+        Err(errors)
+    }
+
+    /// Validate the flake at the given path, as thoroughly as told by `validation`.
+    fn check_flake(&self, name: &str, path: &Path, validation: Validation) -> Result<(), Vec<Error>> {
+        if validation == Validation::None {
+            return Ok(());
+        }
+        if !path.join("flake.nix").is_file() {
+            return Err(vec![Error::NotAFlake(path.display().to_string())]);
+        }
+        lock::LockFile::read(path)?;
+
+        let mut progress = self.progress(name, false);
+        let mut control = Control::new(&mut progress);
+        let output = match validation {
+            Validation::None | Validation::Syntax => return Ok(()),
+            Validation::Metadata => self.backend.metadata(path, &mut control)?,
+            Validation::Show => self.backend.check(path, &mut control)?,
+        };
+        self.perform(output, &mut Log::Direct)?;
+        Ok(())
+    }
+
+    /// Validate the given flake, or every tracked flake, and print wether each one is valid.
+    fn check_flakes(
+        &self,
+        name: Option<String>,
+        validation: Option<Validation>,
+    ) -> Result<(), Vec<Error>> {
+        let validation = validation.unwrap_or(self.settings.validation);
+        let flakes: Vec<(&String, &Flake)> = match name {
+            Some(name) => match self.flakes.get_key_value(&name) {
+                Some(flake) => vec![flake],
+                None => return Err(vec![Error::MissingFlake(name)]),
+            },
+            None => self.flakes.iter().collect(),
+        };
+        let mut invalid = 0;
+        for (name, flake) in flakes {
+            match self.check_flake(name, &flake.path, validation) {
+                Ok(()) => println!(
+                    "{}: {}",
+                    name,
+                    apply_style(Style::new().fg(Colour::Green), "valid", self.stdout_style)
+                ),
+                Err(errors) => {
+                    invalid += 1;
+                    println!(
+                        "{}: {}",
+                        name,
+                        apply_style(Style::new().fg(Colour::Red), "invalid", self.stdout_style)
+                    );
+                    for err in errors {
+                        error(&err.msg(), self.stderr_style);
                     }
                 }
             }
-            push_error(&mut current_error);
-            return Err(v);
+        }
+        if invalid > 0 {
+            return Err(vec![Error::Invalid(invalid)]);
         }
         Ok(())
     }
 
-    /// Checks that a given path contains a valid nix flake by running
-    /// `nix flake show` and checking the exit code.
-    fn check_flake(&self, path: &Path) -> Result<(), Vec<Error>> {
-        let mut cmd = Command::new("nix");
-        self.perform(cmd.arg("flake").arg("show").arg(path))
+    /// Return the metadata of the flake at the given path, from `nix flake metadata`.
+    fn flake_metadata(&self, name: &str, path: &Path) -> Result<serde_json::Value, Vec<Error>> {
+        let mut progress = self.progress(name, false);
+        let output = self.backend.metadata(path, &mut Control::new(&mut progress))?;
+        let stdout = self.perform(output, &mut Log::Direct)?;
+        serde_json::from_slice(&stdout)
+            .map_err(|e| vec![Error::Parse(path.display().to_string(), e.to_string())])
     }
 
-    /// Update the flake at the given path by running `nix flake update`.
-    fn update_flake(&self, path: &Path, args: &[String]) -> Result<(), Vec<Error>> {
-        let mut cmd = Command::new("nix");
-        self.perform(cmd.arg("flake").arg("update").arg(path).args(args))
+    /// Update the flake at the given path by running `nix flake update`, as told by `request`.
+    /// Its inputs provided by the hub are locked to the revision of the hub.
+    /// Transient failures are tried again, and the number of retries is returned with the result.
+    fn update_flake(
+        &self,
+        path: &Path,
+        request: &UpdateRequest,
+        log: &mut Log,
+        control: &mut Control,
+    ) -> (Result<(), Vec<Error>>, u32) {
+        let retry = request.retry;
+        let mut retries = 0;
+        loop {
+            let result = self
+                .backend
+                .update(path, request.inputs, request.args, control)
+                .and_then(|output| self.perform(output, log));
+            match result {
+                Err(errors) if retries < retry.retries && errors.iter().all(Error::is_transient) => {
+                    let backoff = retry.backoff(retries);
+                    retries += 1;
+                    for err in errors {
+                        log.warn(err.msg(), self.stderr_style);
+                    }
+                    log.warn(
+                        format!(
+                            "retrying in {:.1}s ({}/{})",
+                            backoff.as_secs_f64(),
+                            retries,
+                            retry.retries
+                        ),
+                        self.stderr_style,
+                    );
+                    if let Err(errors) = control.wait(backoff) {
+                        return (Err(errors), retries);
+                    }
+                }
+                result => return (result.map(|_| ()), retries),
+            }
+        }
+    }
+
+    /// Run the verification steps of the flake at the given path, stopping at the first failure.
+    fn verify_flake(
+        &self,
+        path: &Path,
+        steps: &[Verification],
+        log: &mut Log,
+        control: &mut Control,
+    ) -> Result<(), Vec<Error>> {
+        for step in steps {
+            self.perform(self.backend.verify(path, step, control)?, log)?;
+        }
+        Ok(())
+    }
+
+    /// Regenerate and update the hub, and return the locked reference of its inputs.
+    /// If some inputs are given, only those are updated and returned.
+    fn update_hub(
+        &self,
+        inputs: &[String],
+        quiet: bool,
+        timeout: Option<Duration>,
+    ) -> Result<BTreeMap<String, HubInput>, Vec<Error>> {
+        let selected: Vec<String> = if inputs.is_empty() {
+            self.hub.keys().cloned().collect()
+        } else {
+            inputs
+                .iter()
+                .filter(|input| self.hub.contains_key(*input))
+                .cloned()
+                .collect()
+        };
+        if selected.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+
+        let hub_dir = self.hub_dir();
+        // Concurrent runs would race on the files of the hub.
+        let _lock = self.lock_flake(hub::HUB_DIR, &hub_dir)?;
+        hub::write_flake(&hub_dir, &self.hub)?;
+        let mut progress = self.progress(hub::HUB_DIR, quiet);
+        let mut control = Control {
+            timeout,
+            ..Control::new(&mut progress)
+        };
+        if !inputs.is_empty() {
+            // Lock the inputs newly added to the hub, which are not among the selected ones.
+            let output = self.backend.lock(&hub_dir, &[], &mut control)?;
+            self.perform(output, &mut Log::Direct)?;
+        }
+        let updated = if inputs.is_empty() { inputs } else { &selected };
+        let output = self.backend.update(&hub_dir, updated, &[], &mut control)?;
+        self.perform(output, &mut Log::Direct)?;
+        let mut locked = hub::locked_inputs(&hub_dir)?;
+        locked.retain(|name, _| selected.contains(name));
+        Ok(locked)
+    }
+
+    /// Return a function printing what nix does on the given flake while it runs,
+    /// each line being prefixed by the name of the flake. It prints nothing if `quiet`.
+    fn progress<'a>(&'a self, name: &'a str, quiet: bool) -> impl FnMut(&str) + 'a {
+        move |line| {
+            if let Some(text) = diagnostic::progress(line, self.verbose).filter(|_| !quiet) {
+                let prefix = format!("[{}]", name);
+                eprintln!(
+                    "{} {}",
+                    apply_style(Style::new().dimmed(), prefix, self.stderr_style),
+                    text
+                );
+            }
+        }
+    }
+
+    /// Return the path of the hub directory.
+    fn hub_dir(&self) -> PathBuf {
+        self.config_path.with_file_name(hub::HUB_DIR)
     }
 
     /// Return a shared reference to a tracked flake, if it exists, and an error otherwise.
@@ -393,6 +986,36 @@ impl Interface {
             .ok_or_else(|| vec![Error::MissingFlake(name.to_owned())])
     }
 
+    /// Return the names of the tracked flakes selected by `tags`, among the given
+    /// one if any, warning if there is none.
+    fn select_flakes(&self, name: Option<String>, tags: &TagFilter) -> Result<Vec<String>, Vec<Error>> {
+        let names: Vec<String> = match name {
+            Some(name) => {
+                let flake = self.get_flake(&name)?;
+                tags.matches(flake).then_some(name).into_iter().collect()
+            }
+            None => self
+                .flakes
+                .iter()
+                .filter(|(_, flake)| tags.matches(flake))
+                .map(|(name, _)| name.clone())
+                .collect(),
+        };
+        if names.is_empty() {
+            warn("no tracked flake matches the given tags", self.stderr_style);
+        }
+        Ok(names)
+    }
+
+    /// Lock the flake at `path`, failing if another process holds it, unless `--wait` is given.
+    fn lock_flake(&self, name: &str, path: &Path) -> Result<File, Vec<Error>> {
+        let msg = format!("waiting for another snow-plow to finish updating flake `{}`", name);
+        guard::flake(&self.state_dir, path, self.access.wait, || {
+            warn(&msg, self.stderr_style)
+        })?
+        .ok_or_else(|| vec![Error::FlakeBusy(name.to_owned())])
+    }
+
     /// Return a mutable reference to a tracked flake, if it exists, and an error otherwise.
     fn get_flake_mut(&mut self, name: &str) -> Result<&mut Flake, Vec<Error>> {
         self.flakes
@@ -410,33 +1033,32 @@ impl Interface {
         }
     }
 
-    /// The fallible part of the constructor.
-    fn init(&mut self, config_dir: PathBuf) -> Result<(), Vec<Error>> {
-        if !self.config_path.exists() {
+    /// The fallible part of the constructor, reading the registry.
+    fn init(
+        config_dir: &Path,
+        config_path: &Path,
+        stderr_style: bool,
+    ) -> Result<Registry, Vec<Error>> {
+        if !config_path.exists() {
             DirBuilder::new()
                 .recursive(true)
-                .create(&config_dir)
-                .and_then(|()| File::create_new(&self.config_path))
+                .create(config_dir)
                 .map_err(|e| vec![Error::Io(e, config_dir.display().to_string())])?;
-        }
 
-        let file = File::open(&self.config_path)
-            .map_err(|e| vec![Error::Io(e, config_dir.display().to_string())])?;
-        let mut reader = csv::Reader::from_reader(file);
-        for result in reader.deserialize() {
-            let named_flake: NamedFlake = result.map_err(|e| vec![Error::Internal(Box::new(e))])?;
-            let (name, flake) = named_flake.into();
-            if let Some(old_flake) = self.flakes.insert(name.clone(), flake) {
+            if config_dir.join(LEGACY_CONFIG_FILE).exists() {
+                let backup_path = registry::migrate(config_dir, config_path)?;
                 let msg = format!(
-                    "flake `{}` is present several time in the file. \"{}\" has been removed.",
-                    name,
-                    old_flake.path.display(),
+                    "the registry has been migrated to \"{}\", the old one has been kept at \"{}\"",
+                    config_path.display(),
+                    backup_path.display(),
                 );
-                warn(&msg, self.stderr_style);
+                warn(&msg, stderr_style);
+            } else {
+                Registry::default().save(config_path)?;
             }
         }
 
-        Ok(())
+        Registry::load(config_path)
     }
 
     /// Generate the man page for the given Command.
@@ -467,7 +1089,7 @@ impl Drop for Interface {
     fn drop(&mut self) {
         if !self.cleaned {
             Self::handle_errors(
-                vec![Error::Internal(format!("unexpected exit").into())],
+                vec![Error::Internal("unexpected exit".into())],
                 true,
                 self.stderr_style,
             );
@@ -476,6 +1098,57 @@ impl Drop for Interface {
     }
 }
 
+/// How to update a flake.
+struct UpdateRequest<'a> {
+    /// The inputs to update, or all of them if empty.
+    inputs: &'a [String],
+    /// The arguments passed further to nix, starting with the overrides locking
+    /// the inputs of the flake to the hub.
+    args: &'a [String],
+    /// How the update is tried again after a transient failure.
+    retry: RetryPolicy,
+}
+
+/// Where the messages about the update of a flake are written.
+enum Log {
+    /// Messages are printed as they come.
+    Direct,
+    /// Messages are kept, to be printed once the update is over, along with
+    /// wether they are warnings.
+    Buffered(Vec<(String, bool)>),
+}
+
+impl Log {
+    /// Print a message on stdout, or keep it for later.
+    fn info(&mut self, msg: String) {
+        match self {
+            Log::Direct => println!("{}", msg),
+            Log::Buffered(messages) => messages.push((msg, false)),
+        }
+    }
+
+    /// Raise a warning, or keep it for later.
+    fn warn(&mut self, msg: String, stderr_style: bool) {
+        match self {
+            Log::Direct => warn(&msg, stderr_style),
+            Log::Buffered(messages) => messages.push((msg, true)),
+        }
+    }
+
+    /// Print the kept messages.
+    fn flush(&mut self, stderr_style: bool) {
+        if let Log::Buffered(messages) = self {
+            for (msg, is_warning) in messages.drain(..) {
+                if is_warning {
+                    warn(&msg, stderr_style);
+                } else {
+                    println!("{}", msg);
+                }
+            }
+        }
+    }
+}
+
 /// Apply the given style to the input if `style_enabled` is true,
 /// or the default style else.
 fn apply_style<'a, S, I>(style: Style, input: I, style_enabled: bool) -> ANSIGenericString<'a, S>
@@ -491,6 +1164,27 @@ where
     }
 }
 
+/// Used to skip the serialization of fields with their default value.
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// Parse an environment variable given as `NAME=VALUE` on the command line.
+fn parse_env(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_owned(), value.to_owned())),
+        _ => Err(format!("`{}` is not of the form NAME=VALUE", arg)),
+    }
+}
+
+/// Parse a delay given in seconds on the command line.
+fn parse_delay(arg: &str) -> Result<f64, String> {
+    let delay = arg
+        .parse()
+        .map_err(|_| format!("`{}` is not a number of seconds", arg))?;
+    registry::check_delay(delay)
+}
+
 /// Log a message on stderr.
 fn log(msg: &str, level: &str) {
     eprintln!("snow-plow: {}: {}", level, msg);
@@ -535,9 +1229,41 @@ pub struct Cli {
     /// or $HOME/.config/snow-plow)
     #[arg(long, short, global = true, env = "SNOW_PLOW_CONFIG")]
     pub config: Option<PathBuf>,
+    /// The directory SnowPlow will use for saving the backups of lock files and the journal.
+    ///
+    /// If it is not provided through the command line, it will be read from
+    /// the environment variable SNOW_PLOW_STATE. If it is not present,
+    /// SnowPlow will try the default locations for the system ($XDG_STATE_HOME/snow-plow
+    /// or $HOME/.local/state/snow-plow)
+    #[arg(long, global = true, env = "SNOW_PLOW_STATE")]
+    pub state: Option<PathBuf>,
     /// Control when the output should be formatted with ANSI escape code.
     #[arg(long, short, default_value = "auto", global = true)]
     pub style: ColorChoice,
+    /// Print everything nix prints, and not only its errors.
+    #[arg(long, short, global = true)]
+    pub verbose: bool,
+    /// The nix executable to run, instead of the one set in the registry, or `nix` from the `PATH`.
+    #[arg(long, global = true, env = "SNOW_PLOW_NIX", value_name = "PATH")]
+    pub nix: Option<PathBuf>,
+    /// An option added to every call to nix, after the ones set in the registry.
+    /// Can be given several times, for instance
+    /// `--nix-option=--extra-experimental-features --nix-option='nix-command flakes'`.
+    #[arg(
+        long = "nix-option",
+        global = true,
+        value_name = "OPTION",
+        allow_hyphen_values = true
+    )]
+    pub nix_options: Vec<String>,
+    /// An environment variable set for every call to nix, such as `NIX_CONFIG`, in
+    /// addition to the ones set in the registry. Can be given several times.
+    #[arg(long = "nix-env", global = true, value_name = "NAME=VALUE", value_parser = parse_env)]
+    pub nix_env: Vec<(String, String)>,
+    /// Wait for the other running instances of SnowPlow to release the registry or
+    /// the flakes they use, instead of failing.
+    #[arg(long, global = true)]
+    pub wait: bool,
 }
 
 /// The different commands of SnowPlow.
@@ -551,31 +1277,312 @@ pub enum Commands {
         /// The path of directory containing a `flake.nix`.
         /// It need not be canonical, but it will be made absolute.
         path: PathBuf,
+        /// How thoroughly the flake is validated, instead of `validation` in the registry settings.
+        #[arg(long, value_name = "LEVEL")]
+        level: Option<Validation>,
+        /// Do not validate the flake, as with `--level none`.
+        #[arg(long, conflicts_with = "level")]
+        no_check: bool,
+    },
+    /// Change the arguments given to nix and the inputs overridden when updating a flake.
+    ///
+    /// They are given to nix before the arguments following `--` on the command line
+    /// of `snow-plow update`. `snow-plow info` shows them.
+    Set {
+        name: String,
+        #[command(flatten)]
+        options: SetOptions,
+    },
+    /// Print the registry, with the tracked flakes, the settings and the hub, so that it can be
+    /// imported on another machine. The paths inside the home directory are written relative to `~`.
+    Export {
+        #[arg(long, default_value = "toml")]
+        format: Format,
+        #[command(flatten)]
+        tags: TagFilter,
+    },
+    /// Track the flakes exported by `snow-plow export` in the given file, or `-`
+    /// for the standard input, along with the settings and the hub inputs.
+    /// The paths relative to `~` are resolved in the home directory.
+    Import {
+        file: PathBuf,
+        /// The format of the file, guessed from its extension by default.
+        #[arg(long)]
+        format: Option<Format>,
+        /// What to do with the flakes whose name is already tracked, and with the
+        /// settings and hub inputs which differ from the current ones.
+        #[arg(long, default_value = "fail")]
+        on_conflict: Conflict,
+    },
+    /// Validate the given flake, or every tracked flake, as when it was added.
+    /// The exit status is 3 if at least one flake is not valid.
+    Check {
+        name: Option<String>,
+        /// How thoroughly the flakes are validated, instead of `validation` in the registry settings.
+        #[arg(long, value_name = "LEVEL")]
+        level: Option<Validation>,
+    },
+    /// Enable a previously disabled flake, or the flakes with the given tags,
+    /// so they will be updated by SnowPlow.
+    Enable {
+        #[arg(required_unless_present_any = ["tags", "exclude_tags"])]
+        name: Option<String>,
+        #[command(flatten)]
+        tags: TagFilter,
+    },
+    /// Disable a flake, or the flakes with the given tags, so they will stop
+    /// being updated by `snow-plow update`
+    Disable {
+        #[arg(required_unless_present_any = ["tags", "exclude_tags"])]
+        name: Option<String>,
+        #[command(flatten)]
+        tags: TagFilter,
     },
-    /// Enable a previously disabled flake, so it will be updated by SnowPlow.
-    Enable { name: String },
-    /// Disable a flake, so it will stop being updated by `snow-plow update`
-    Disable { name: String },
     /// Remove a flake from the list, so that SnowPlow doesn't manage it anymore.
     Remove { name: String },
+    /// Rename a tracked flake, keeping its settings and its backups.
+    Rename { old_name: String, new_name: String },
+    /// Change the directory of a tracked flake, which is validated as when it is added.
+    Move { name: String, path: PathBuf },
+    /// Open the registry in `$VISUAL` or `$EDITOR`. It is only saved once it is
+    /// valid, the editor being opened again until it is. Exiting the editor with
+    /// an error leaves the registry unchanged.
+    Edit,
     /// Update the specified flake if a name is given, or all enabled flakes at once if no name is given.
+    ///
+    /// If the hub has inputs, it is updated first, and the inputs of the flakes
+    /// with the same name are locked to the same revision as in the hub.
+    ///
+    /// After the update of a flake, its verification steps are run, and its lock
+    /// file is restored if one of them fails. They are set in the registry, for
+    /// instance `verify = ["check", { eval = "<attribute>" }, { build = "<output>" }]`.
+    ///
+    /// The inputs changed by the update of each flake are printed, with their
+    /// old and new revision. A summary of the run is printed at the end. The exit status is 3 if at
+    /// least one flake failed to be updated, 2 for a wrong usage, and 1 for any other error.
     Update {
         name: Option<String>,
+        #[command(flatten)]
+        options: UpdateOptions,
         /// Optional arguments to pass further to nix.
-        #[clap(last=true)]
-        args: Vec<String>
+        #[clap(last = true)]
+        args: Vec<String>,
+    },
+    /// Restore the lock file of a flake as it was before an update, or of every
+    /// flake changed by an update.
+    ///
+    /// Without `--run`, the lock files are restored from the last run which
+    /// changed the given flake, or from the last run if no name is given.
+    Rollback {
+        name: Option<String>,
+        /// The identifier of the run to roll back, such as `20240131-083000.123456`.
+        #[arg(long)]
+        run: Option<String>,
+    },
+    /// Show the journal of the previous updates, with the changed inputs and the errors.
+    History {
+        /// Only show the updates of this flake.
+        name: Option<String>,
+        /// Only show the updates since this date, such as `2024-01-31`.
+        #[arg(long)]
+        since: Option<NaiveDate>,
+        /// Only show the updates until this date, included.
+        #[arg(long)]
+        until: Option<NaiveDate>,
+    },
+    /// Manage the hub, a flake holding the canonical version of the inputs
+    /// shared by the tracked flakes.
+    Hub {
+        #[command(subcommand)]
+        command: HubCommands,
+    },
+    /// Tag flakes, so that they can be selected together with `--tag` and `--exclude-tag`.
+    Tag {
+        #[command(subcommand)]
+        command: TagCommands,
     },
     /// List all tracked flakes, their path and status.
     List {
         #[command(flatten)]
         filter: ListFilter,
+        #[command(flatten)]
+        tags: TagFilter,
     },
     /// Generate completion for the given shell, in the current directory.
     GenCompletion { shell: Shell },
     /// Generate man pages, in the current directory.
     GenMan,
-    /// Show the path and status of a given flake.
-    Info { name: String },
+    /// Show the path and status of a given flake, or of the flakes with the given tags.
+    Info {
+        #[arg(required_unless_present_any = ["tags", "exclude_tags"])]
+        name: Option<String>,
+        #[command(flatten)]
+        tags: TagFilter,
+        /// Also show the description and the date of the last change of the flakes, as read by nix.
+        #[arg(long)]
+        metadata: bool,
+    },
+}
+
+impl Commands {
+    /// Wether the command changes the registry.
+    fn writes_registry(&self) -> bool {
+        match self {
+            Commands::Add { .. }
+            | Commands::Enable { .. }
+            | Commands::Disable { .. }
+            | Commands::Remove { .. }
+            | Commands::Rename { .. }
+            | Commands::Move { .. }
+            | Commands::Edit
+            | Commands::Set { .. }
+            | Commands::Import { .. } => true,
+            Commands::Tag { command } => !matches!(command, TagCommands::List),
+            Commands::Hub { command } => !matches!(command, HubCommands::List),
+            Commands::Update { .. }
+            | Commands::Rollback { .. }
+            | Commands::History { .. }
+            | Commands::Check { .. }
+            | Commands::Export { .. }
+            | Commands::List { .. }
+            | Commands::GenCompletion { .. }
+            | Commands::GenMan
+            | Commands::Info { .. } => false,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum TagCommands {
+    /// Add a tag to the given flakes.
+    Add {
+        tag: String,
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+    /// Remove a tag from the given flakes.
+    Remove {
+        tag: String,
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+    /// List the tags and the flakes which have them.
+    List,
+}
+
+/// The commands managing the hub.
+#[derive(Subcommand)]
+pub enum HubCommands {
+    /// Add an input to the hub, or change its flake reference.
+    Add {
+        /// The name of the input, which is matched against the inputs of the tracked flakes.
+        name: String,
+        /// The flake reference of the input, for instance `github:NixOS/nixpkgs/nixos-unstable`.
+        /// Only the flakes referencing the same source share the input.
+        url: String,
+    },
+    /// Remove an input from the hub.
+    Remove { name: String },
+    /// List the inputs of the hub and their locked reference.
+    List,
+}
+
+/// The options of the update command.
+#[derive(Args)]
+pub struct UpdateOptions {
+    /// Only update the given inputs, skipping the flakes which have none of them.
+    /// Can be given several times.
+    #[arg(long = "input", short, value_name = "INPUT")]
+    pub inputs: Vec<String>,
+    /// Stop starting new updates as soon as one flake failed to be updated.
+    #[arg(long)]
+    pub fail_fast: bool,
+    /// Only print the inputs changed by the update of each flake.
+    #[arg(long)]
+    pub diff_only: bool,
+    /// Only print the result of the update of each flake, and the errors, instead of
+    /// what nix does while it runs, the changed inputs, and the summary.
+    #[arg(long, short, conflicts_with = "diff_only")]
+    pub quiet: bool,
+    /// Commit the lock file of each updated flake living in a git repository,
+    /// with a message listing the changed inputs. Flakes whose lock file already
+    /// has uncommitted changes are not updated.
+    ///
+    /// It can also be enabled for a single flake with `commit = true` in the registry.
+    #[arg(long)]
+    pub commit: bool,
+    /// Update each flake living in a git repository in a temporary work tree, on a new
+    /// branch with the given name, and commit its lock file there, leaving the checked
+    /// out branch untouched. The branch is deleted if the lock file did not change.
+    ///
+    /// In the name, `{date}` stands for the date of the run, `{time}` for its time and `{name}`
+    /// for the name of the flake, for instance `snow-plow/update-{date}`. Use `{name}` when several
+    /// flakes share a repository. A flake whose branch already exists, from a previous run, is
+    /// skipped: use `{time}` to run several times a day.
+    #[arg(long, value_name = "BRANCH")]
+    pub branch: Option<String>,
+    /// Do not run the verification steps of the flakes after their update.
+    #[arg(long)]
+    pub no_verify: bool,
+    /// Do not update the hub, nor lock the inputs of the flakes to it.
+    #[arg(long)]
+    pub no_hub: bool,
+    /// How long the update of each flake may last, in seconds, after which nix is killed
+    /// and the update fails. A flake may have its own with `timeout` in the registry.
+    #[arg(long, value_name = "SECONDS")]
+    pub timeout: Option<u64>,
+    /// How many times an update failing because of the network or a rate limit
+    /// is tried again, instead of `retry.retries` in the registry settings.
+    #[arg(long, value_name = "N")]
+    pub retries: Option<u32>,
+    /// How long to wait before the first retry, doubled before each next one,
+    /// instead of `retry.delay` in the registry settings.
+    #[arg(long, value_name = "SECONDS", value_parser = parse_delay)]
+    pub retry_delay: Option<f64>,
+    /// The number of flakes updated at the same time, when updating all flakes.
+    /// The output of each flake is printed once it is done, in order.
+    #[arg(long, short, default_value = "1")]
+    pub jobs: NonZeroUsize,
+    #[command(flatten)]
+    pub tags: TagFilter,
+}
+
+/// The options of the set command.
+#[derive(Args)]
+pub struct SetOptions {
+    /// Add an argument given to nix when updating the flake, such as `--impure`.
+    /// Can be given several times.
+    #[arg(long = "arg", value_name = "ARG", allow_hyphen_values = true)]
+    pub args: Vec<String>,
+    /// Remove the arguments previously set, before adding the ones given with `--arg`.
+    #[arg(long)]
+    pub clear_args: bool,
+    /// Override an input of the flake with the given flake reference whenever it is updated,
+    /// such as `--override-input secrets path:/run/secrets`. Can be given several times.
+    #[arg(long, num_args = 2, value_names = ["INPUT", "REF"])]
+    pub override_input: Vec<String>,
+    /// Stop overriding an input. Can be given several times.
+    #[arg(long, value_name = "INPUT")]
+    pub remove_override: Vec<String>,
+}
+
+/// The selection of flakes by their tags.
+#[derive(Args)]
+pub struct TagFilter {
+    /// Only select the flakes with this tag, or with any of them if it is given several times.
+    #[arg(long = "tag", value_name = "TAG")]
+    pub tags: Vec<String>,
+    /// Do not select the flakes with this tag. Can be given several times.
+    #[arg(long = "exclude-tag", value_name = "TAG")]
+    pub exclude_tags: Vec<String>,
+}
+
+impl TagFilter {
+    /// Wether the flake is selected.
+    pub fn matches(&self, flake: &Flake) -> bool {
+        (self.tags.is_empty() || self.tags.iter().any(|tag| flake.tags.contains(tag)))
+            && !self.exclude_tags.iter().any(|tag| flake.tags.contains(tag))
+    }
 }
 
 /// Filters for the list commands.
@@ -598,6 +1605,9 @@ fn main() {
     Cli::command().debug_assert();
 
     let (stdout_style, stderr_style) = Interface::style(cli.style);
+    if let Err(errors) = signal::install() {
+        Interface::handle_errors(errors, true, stderr_style);
+    }
 
     let res = match cli.commands {
         Commands::GenCompletion { shell } => Some(Interface::generate_completion(shell)),
@@ -624,19 +1634,106 @@ fn main() {
         }
     };
 
-    let mut interface = Interface::new(config_path, stdout_style, stderr_style);
+    let state_path = if let Some(state_path) = cli.state {
+        state_path
+    } else {
+        let project_dir = ProjectDirs::from("", "", "snow-plow");
+        let state_dir = project_dir.as_ref().map(|project_dir| {
+            project_dir
+                .state_dir()
+                .unwrap_or_else(|| project_dir.data_local_dir())
+                .to_owned()
+        });
+        match state_dir.ok_or_else(|| vec![Error::NoState]) {
+            Ok(state_dir) => state_dir,
+            Err(errors) => {
+                Interface::handle_errors(errors, true, stderr_style);
+                unreachable!();
+            }
+        }
+    };
+
+    let nix = NixSettings {
+        program: cli.nix,
+        options: cli.nix_options,
+        env: cli.nix_env.into_iter().collect(),
+    };
+    let backend = |settings: &Settings| -> Result<Box<dyn Backend>, Vec<Error>> {
+        // Only debug builds, which the tests of the command line use, can replace nix.
+        #[cfg(debug_assertions)]
+        if let Some(script) = env::var_os("SNOW_PLOW_FAKE_NIX") {
+            return Ok(Box::new(FakeBackend::load(Path::new(&script))?));
+        }
+        let mut settings = settings.nix.clone();
+        settings.merge(nix);
+        Ok(Box::new(NixBackend::new(settings)))
+    };
+
+    let access = Access {
+        write: cli.commands.writes_registry(),
+        wait: cli.wait,
+    };
+    let mut interface = Interface::new(
+        config_path,
+        state_path,
+        access,
+        backend,
+        cli.verbose,
+        stdout_style,
+        stderr_style,
+    );
 
     let res = match cli.commands {
-        Commands::Add { name, path } => interface.add_flake(name, path),
-        Commands::Enable { name } => interface.enable_flake(name),
-        Commands::Disable { name } => interface.disable_flake(name),
+        Commands::Add {
+            name,
+            path,
+            level,
+            no_check,
+        } => {
+            let level = if no_check { Some(Validation::None) } else { level };
+            interface.add_flake(name, path, level)
+        }
+        Commands::Set { name, options } => interface.set_flake(name, options),
+        Commands::Export { format, tags } => interface.export_flakes(format, tags),
+        Commands::Import {
+            file,
+            format,
+            on_conflict,
+        } => interface.import_flakes(file, format, on_conflict),
+        Commands::Check { name, level } => interface.check_flakes(name, level),
+        Commands::Enable { name, tags } => interface.enable_flake(name, tags),
+        Commands::Disable { name, tags } => interface.disable_flake(name, tags),
         Commands::Remove { name } => interface.remove_flake(name),
-        Commands::Update { name, args } => interface.update_flakes(name, args),
-        Commands::List { filter } => interface.list_flakes(filter),
+        Commands::Rename { old_name, new_name } => interface.rename_flake(old_name, new_name),
+        Commands::Move { name, path } => interface.move_flake(name, path),
+        Commands::Edit => interface.edit_registry(),
+        Commands::Update {
+            name,
+            options,
+            args,
+        } => interface.update_flakes(name, options, args),
+        Commands::Rollback { name, run } => interface.rollback(name, run),
+        Commands::History { name, since, until } => interface.show_history(name, since, until),
+        Commands::Hub { command } => match command {
+            HubCommands::Add { name, url } => interface.add_hub_input(name, url),
+            HubCommands::Remove { name } => interface.remove_hub_input(name),
+            HubCommands::List => interface.list_hub_inputs(),
+        },
+        Commands::Tag { command } => match command {
+            TagCommands::Add { tag, names } => interface.add_tag(tag, names),
+            TagCommands::Remove { tag, names } => interface.remove_tag(tag, names),
+            TagCommands::List => interface.list_tags(),
+        },
+        Commands::List { filter, tags } => interface.list_flakes(filter, tags),
         Commands::GenCompletion { .. } | Commands::GenMan => unreachable!(),
-        Commands::Info { name } => interface.info_flake(name),
+        Commands::Info { name, tags, metadata } => interface.info_flake(name, tags, metadata),
+    };
+    let res = match res {
+        Ok(()) => interface.clean(),
+        // What was done before the interruption must be kept.
+        Err(errors) if signal::interrupted() => interface.clean().and(Err(errors)),
+        Err(errors) => Err(errors),
     };
-    let res = res.and_then(|()| interface.clean());
     if let Err(errors) = res {
         Interface::handle_errors(errors, true, interface.stderr_style);
     }
@@ -0,0 +1,437 @@
+//! Detection of the installed nix, of the command lines it understands, and
+//! the backend running it.
+
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+    process::{Command, Stdio},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    backend::{Backend, Control, Output},
+    registry::NixSettings,
+    Error, Verification,
+};
+
+/// The implementations of nix SnowPlow knows about.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Implementation {
+    Nix,
+    Lix,
+}
+
+/// The version of the installed nix.
+#[derive(Clone, Copy)]
+pub struct NixVersion {
+    pub implementation: Implementation,
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl NixVersion {
+    /// The oldest version of nix with flakes.
+    const MINIMUM: (u32, u32) = (2, 4);
+
+    /// Run `nix --version` with the given command, and parse its output.
+    pub fn detect(mut cmd: Command) -> Result<Self, Vec<Error>> {
+        let output = cmd
+            .arg("--version")
+            .output()
+            .map_err(|e| vec![Error::Io(e, cmd.get_program().to_string_lossy().into())])?;
+        Self::from_output(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// Parse the output of `nix --version`, and check that the version is supported.
+    pub fn from_output(output: &str) -> Result<Self, Vec<Error>> {
+        let version =
+            Self::parse(output).ok_or_else(|| vec![Error::UnsupportedNix(output.trim().into())])?;
+        if (version.major, version.minor) < Self::MINIMUM {
+            return Err(vec![Error::UnsupportedNix(version.to_string())]);
+        }
+        Ok(version)
+    }
+
+    /// Parse the output of `nix --version`, for instance `nix (Nix) 2.18.1`
+    /// or `nix (Lix, like Nix) 2.91.0`.
+    fn parse(output: &str) -> Option<Self> {
+        let line = output.lines().next()?;
+        let implementation = if line.contains("Lix") {
+            Implementation::Lix
+        } else {
+            Implementation::Nix
+        };
+        // Pre-releases have a suffix, such as `2.24.0pre20240101_abcdef`.
+        let mut numbers = line.split_whitespace().last()?.split('.').map(|part| {
+            let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse().ok()
+        });
+        Some(NixVersion {
+            implementation,
+            major: numbers.next()??,
+            minor: numbers.next()??,
+            patch: numbers.next().flatten().unwrap_or(0),
+        })
+    }
+
+    /// Wether `nix flake update` takes the names of inputs as positional
+    /// arguments and the flake through `--flake`. This is the case since
+    /// Nix 2.19, and since Lix 2.91 which adopted the same interface.
+    fn positional_update(&self) -> bool {
+        match self.implementation {
+            Implementation::Nix => (self.major, self.minor) >= (2, 19),
+            Implementation::Lix => (self.major, self.minor) >= (2, 91),
+        }
+    }
+
+    /// Add the arguments updating the given inputs of the flake at `path` to a `nix` command,
+    /// or every input if none is given.
+    pub fn update_args<'a>(
+        &self,
+        cmd: &'a mut Command,
+        path: &Path,
+        inputs: &[String],
+    ) -> &'a mut Command {
+        if self.positional_update() {
+            cmd.arg("flake")
+                .arg("update")
+                .args(inputs)
+                .arg("--flake")
+                .arg(path)
+        } else if inputs.is_empty() {
+            cmd.arg("flake").arg("update").arg(path)
+        } else {
+            cmd.arg("flake").arg("lock").arg(path);
+            for input in inputs {
+                cmd.arg("--update-input").arg(input);
+            }
+            cmd
+        }
+    }
+
+    /// Add the arguments running a verification step on the flake at `path` to a `nix` command.
+    pub fn verify_args<'a>(
+        &self,
+        cmd: &'a mut Command,
+        path: &Path,
+        step: &Verification,
+    ) -> &'a mut Command {
+        match step {
+            Verification::Check => cmd.arg("flake").arg("check").arg(path),
+            Verification::Eval(attr) => cmd
+                .arg("eval")
+                .arg(format!("{}#{}", path.display(), attr)),
+            Verification::Build(output) => cmd
+                .arg("build")
+                .arg("--no-link")
+                .arg(format!("{}#{}", path.display(), output)),
+        }
+    }
+
+    /// Add the arguments showing the outputs of the flake at `path` to a `nix` command.
+    pub fn show_args<'a>(&self, cmd: &'a mut Command, path: &Path) -> &'a mut Command {
+        cmd.arg("flake").arg("show").arg(path)
+    }
+
+    /// Add the arguments printing the metadata of the flake at `path` to a `nix` command.
+    pub fn metadata_args<'a>(&self, cmd: &'a mut Command, path: &Path) -> &'a mut Command {
+        cmd.arg("flake").arg("metadata").arg("--json").arg(path)
+    }
+
+    /// Add the arguments locking the missing inputs of the flake at `path` to a `nix` command.
+    pub fn lock_args<'a>(&self, cmd: &'a mut Command, path: &Path) -> &'a mut Command {
+        cmd.arg("flake").arg("lock").arg(path)
+    }
+}
+
+/// The backend running the installed nix.
+pub struct NixBackend {
+    settings: NixSettings,
+    /// The version of nix, detected the first time it is needed.
+    version: OnceLock<NixVersion>,
+}
+
+impl NixBackend {
+    pub fn new(settings: NixSettings) -> Self {
+        NixBackend {
+            settings,
+            version: OnceLock::new(),
+        }
+    }
+
+    /// Return a `nix` command with the global options and environment.
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(self.settings.program.as_deref().unwrap_or("nix".as_ref()));
+        cmd.args(&self.settings.options).envs(&self.settings.env);
+        cmd
+    }
+
+    /// Run a `nix` command, built from the version of nix, which reports its messages as JSON.
+    fn run(
+        &self,
+        build: impl FnOnce(&NixVersion, &mut Command),
+        control: &mut Control,
+    ) -> Result<Output, Vec<Error>> {
+        let version = self.version()?;
+        let mut cmd = self.command();
+        cmd.arg("--log-format").arg("internal-json");
+        build(&version, &mut cmd);
+
+        let program = cmd.get_program().to_string_lossy().into_owned();
+        match stream(&mut cmd, control) {
+            Ok(Ok(output)) => Ok(output),
+            Ok(Err(stopped)) => Err(vec![stopped]),
+            Err(e) => Err(vec![Error::Io(e, program)]),
+        }
+    }
+}
+
+/// How often a running command is checked for being stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Run a command, giving each line of its standard error to `control.progress` as soon as it is printed.
+/// The command is killed if `control` says it must be stopped, and the reason is returned.
+fn stream(cmd: &mut Command, control: &mut Control) -> io::Result<Result<Output, Error>> {
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdout_pipe = child.stdout.take().expect("stdout is piped");
+    let stderr_pipe = child.stderr.take().expect("stderr is piped");
+
+    // The pipes are read by their own threads, so that nix never blocks on them, and so
+    // that nix can be killed even if the processes it started keep them open.
+    let stdout_reader = thread::spawn(move || {
+        let mut stdout = Vec::new();
+        stdout_pipe.read_to_end(&mut stdout).map(|_| stdout)
+    });
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || -> io::Result<()> {
+        let mut lines = BufReader::new(stderr_pipe);
+        loop {
+            let mut line = Vec::new();
+            if lines.read_until(b'\n', &mut line)? == 0 || sender.send(line).is_err() {
+                return Ok(());
+            }
+        }
+    });
+
+    let mut stderr = Vec::new();
+    let mut stopped = None;
+    loop {
+        match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(line) => {
+                (control.progress)(String::from_utf8_lossy(&line).trim_end());
+                stderr.extend(line);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if stopped.is_some() {
+            if child.try_wait()?.is_some() {
+                break;
+            }
+        } else if let Some(reason) = control.stopped() {
+            child.kill()?;
+            stopped = Some(reason);
+        }
+    }
+
+    let status = child.wait()?;
+    if let Some(reason) = stopped {
+        return Ok(Err(reason));
+    }
+    let stdout = stdout_reader
+        .join()
+        .expect("the reading thread does not panic")?;
+    Ok(Ok(Output {
+        success: status.success(),
+        stdout,
+        stderr,
+    }))
+}
+
+impl Backend for NixBackend {
+    fn version(&self) -> Result<NixVersion, Vec<Error>> {
+        if let Some(version) = self.version.get() {
+            return Ok(*version);
+        }
+        let version = NixVersion::detect(self.command())?;
+        Ok(*self.version.get_or_init(|| version))
+    }
+
+    fn check(&self, path: &Path, control: &mut Control) -> Result<Output, Vec<Error>> {
+        self.run(
+            |version, cmd| {
+                version.show_args(cmd, path);
+            },
+            control,
+        )
+    }
+
+    fn metadata(
+        &self,
+        path: &Path,
+        control: &mut Control,
+    ) -> Result<Output, Vec<Error>> {
+        self.run(
+            |version, cmd| {
+                version.metadata_args(cmd, path);
+            },
+            control,
+        )
+    }
+
+    fn lock(
+        &self,
+        path: &Path,
+        args: &[String],
+        control: &mut Control,
+    ) -> Result<Output, Vec<Error>> {
+        self.run(
+            |version, cmd| {
+                version.lock_args(cmd, path).args(args);
+            },
+            control,
+        )
+    }
+
+    fn update(
+        &self,
+        path: &Path,
+        inputs: &[String],
+        args: &[String],
+        control: &mut Control,
+    ) -> Result<Output, Vec<Error>> {
+        self.run(
+            |version, cmd| {
+                version.update_args(cmd, path, inputs).args(args);
+            },
+            control,
+        )
+    }
+
+    fn verify(
+        &self,
+        path: &Path,
+        step: &Verification,
+        control: &mut Control,
+    ) -> Result<Output, Vec<Error>> {
+        self.run(
+            |version, cmd| {
+                version.verify_args(cmd, path, step);
+            },
+            control,
+        )
+    }
+}
+
+impl fmt::Display for NixVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.implementation {
+            Implementation::Nix => "Nix",
+            Implementation::Lix => "Lix",
+        };
+        write!(f, "{} {}.{}.{}", name, self.major, self.minor, self.patch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return the arguments given to nix to update `inputs` of the flake at `/flake`.
+    fn update_args(output: &str, inputs: &[&str]) -> Vec<String> {
+        let version = NixVersion::parse(output).unwrap();
+        let inputs: Vec<String> = inputs.iter().map(|input| input.to_string()).collect();
+        let mut cmd = Command::new("nix");
+        version.update_args(&mut cmd, Path::new("/flake"), &inputs);
+        cmd.get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn parse_versions() {
+        let version = NixVersion::parse("nix (Nix) 2.18.1\n").unwrap();
+        assert!(version.implementation == Implementation::Nix);
+        assert_eq!((version.major, version.minor, version.patch), (2, 18, 1));
+
+        let version = NixVersion::parse("nix (Nix) 2.24.0pre20240101_abcdef\n").unwrap();
+        assert!(version.implementation == Implementation::Nix);
+        assert_eq!((version.major, version.minor, version.patch), (2, 24, 0));
+
+        let version =
+            NixVersion::parse("nix (Lix, like Nix) 2.91.0\nSystem type: x86_64-linux\n").unwrap();
+        assert!(version.implementation == Implementation::Lix);
+        assert_eq!((version.major, version.minor, version.patch), (2, 91, 0));
+
+        let version = NixVersion::parse("nix (Nix) 2.4").unwrap();
+        assert_eq!((version.major, version.minor, version.patch), (2, 4, 0));
+
+        assert!(NixVersion::parse("").is_none());
+        assert!(NixVersion::parse("nix (Nix) unknown").is_none());
+    }
+
+    #[test]
+    fn unsupported_versions_are_rejected() {
+        assert!(NixVersion::from_output("nix (Nix) 2.4.0").is_ok());
+        assert!(matches!(
+            NixVersion::from_output("nix (Nix) 2.3.16").err().as_deref(),
+            Some([Error::UnsupportedNix(version)]) if version == "Nix 2.3.16"
+        ));
+        assert!(matches!(
+            NixVersion::from_output("not nix\n").err().as_deref(),
+            Some([Error::UnsupportedNix(output)]) if output == "not nix"
+        ));
+    }
+
+    #[test]
+    fn update_args_follow_the_version() {
+        for output in [
+            "nix (Nix) 2.19.0",
+            "nix (Nix) 2.24.0pre20240101_abcdef",
+            "nix (Lix, like Nix) 2.91.0",
+        ] {
+            assert_eq!(
+                update_args(output, &[]),
+                ["flake", "update", "--flake", "/flake"]
+            );
+            assert_eq!(
+                update_args(output, &["nixpkgs", "home-manager"]),
+                [
+                    "flake",
+                    "update",
+                    "nixpkgs",
+                    "home-manager",
+                    "--flake",
+                    "/flake"
+                ]
+            );
+        }
+        // Lix forked from Nix 2.18, and numbers its versions from 2.90.
+        for output in ["nix (Nix) 2.18.1", "nix (Lix, like Nix) 2.90.0"] {
+            assert_eq!(update_args(output, &[]), ["flake", "update", "/flake"]);
+            assert_eq!(
+                update_args(output, &["nixpkgs", "home-manager"]),
+                [
+                    "flake",
+                    "lock",
+                    "/flake",
+                    "--update-input",
+                    "nixpkgs",
+                    "--update-input",
+                    "home-manager"
+                ]
+            );
+        }
+    }
+}
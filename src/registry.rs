@@ -0,0 +1,270 @@
+//! The on-disk registry of tracked flakes.
+//!
+//! The registry is a TOML file carrying a schema version, so that new fields
+//! can be added without breaking older files. Any field added to [`Flake`]
+//! after the first version must have a default value, so that registries
+//! written by older versions of SnowPlow keep loading.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+
+use crate::{is_default, Error, Flake, Validation};
+
+/// The name of the registry file, inside the configuration directory.
+pub const REGISTRY_FILE: &str = "registry.toml";
+/// The name of the registry file used by SnowPlow 0.1.
+pub const LEGACY_CONFIG_FILE: &str = "config.csv";
+/// The current version of the registry format.
+pub const REGISTRY_VERSION: u32 = 1;
+
+/// The content of the registry file.
+#[derive(Serialize, Deserialize)]
+pub struct Registry {
+    /// The version of the format the file was written with.
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub settings: Settings,
+    /// The inputs of the hub, by name, and their flake reference.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hub: BTreeMap<String, String>,
+    #[serde(default, rename = "flake")]
+    pub flakes: Vec<NamedFlake>,
+}
+
+/// The settings which apply to every flake.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// The number of runs of `snow-plow update` whose lock files are kept for rollbacks.
+    pub backups: usize,
+    #[serde(skip_serializing_if = "is_default")]
+    pub nix: NixSettings,
+    #[serde(skip_serializing_if = "is_default")]
+    pub retry: RetryPolicy,
+    /// How thoroughly the flakes are validated when they are added.
+    #[serde(skip_serializing_if = "is_default")]
+    pub validation: Validation,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            backups: 10,
+            nix: NixSettings::default(),
+            retry: RetryPolicy::default(),
+            validation: Validation::default(),
+        }
+    }
+}
+
+/// How the updates failing because of a transient failure, such as a rate
+/// limit or a network outage, are tried again.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// How many times an update is tried again.
+    pub retries: u32,
+    /// How long to wait before the first retry, in seconds, doubled before each next one.
+    #[serde(deserialize_with = "deserialize_delay")]
+    pub delay: f64,
+}
+
+/// The longest wait before a retry, whatever the delay and the number of retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 2,
+            delay: 5.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Return how long to wait before the given retry, counted from 0.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let seconds = self.delay * 2f64.powi(retry.min(64) as i32);
+        Duration::try_from_secs_f64(seconds)
+            .unwrap_or(MAX_BACKOFF)
+            .min(MAX_BACKOFF)
+    }
+}
+
+/// Check that a delay is a positive number of seconds.
+pub fn check_delay(delay: f64) -> Result<f64, String> {
+    if delay.is_finite() && delay >= 0.0 {
+        Ok(delay)
+    } else {
+        Err(format!("`{}` is not a positive number of seconds", delay))
+    }
+}
+
+fn deserialize_delay<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    check_delay(f64::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+/// How nix is run.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NixSettings {
+    /// The nix executable, `nix` from the `PATH` if none is given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program: Option<PathBuf>,
+    /// The options added to every call to nix, such as
+    /// `["--extra-experimental-features", "nix-command flakes"]`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+    /// The environment variables set for every call to nix, such as `NIX_CONFIG`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+}
+
+impl NixSettings {
+    /// Apply the settings given on the command line over these ones: the
+    /// executable replaces this one, and the options and variables are added.
+    pub fn merge(&mut self, other: NixSettings) {
+        if other.program.is_some() {
+            self.program = other.program;
+        }
+        self.options.extend(other.options);
+        self.env.extend(other.env);
+    }
+}
+
+/// Used for serializing flakes.
+#[derive(Serialize, Deserialize)]
+pub struct NamedFlake {
+    pub name: String,
+    #[serde(flatten)]
+    pub flake: Flake,
+}
+
+/// A line of the legacy `config.csv` file, which is also the format of the CSV exports.
+#[derive(Serialize, Deserialize)]
+struct LegacyFlake {
+    name: String,
+    path: PathBuf,
+    enabled: bool,
+}
+
+impl From<NamedFlake> for (String, Flake) {
+    fn from(named_flake: NamedFlake) -> (String, Flake) {
+        (named_flake.name, named_flake.flake)
+    }
+}
+
+impl From<(String, Flake)> for NamedFlake {
+    fn from((name, flake): (String, Flake)) -> Self {
+        NamedFlake { name, flake }
+    }
+}
+
+impl From<LegacyFlake> for NamedFlake {
+    fn from(legacy: LegacyFlake) -> Self {
+        NamedFlake {
+            name: legacy.name,
+            flake: Flake {
+                path: legacy.path,
+                enabled: legacy.enabled,
+                ..Flake::default()
+            },
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry {
+            version: REGISTRY_VERSION,
+            settings: Settings::default(),
+            hub: BTreeMap::new(),
+            flakes: Vec::new(),
+        }
+    }
+}
+
+impl Registry {
+    /// Read the registry at the given path, upgrading it to the current version if needed.
+    pub fn load(path: &Path) -> Result<Self, Vec<Error>> {
+        let content =
+            fs::read_to_string(path).map_err(|e| vec![Error::Io(e, path.display().to_string())])?;
+        let registry: Registry = toml::from_str(&content)
+            .map_err(|e| vec![Error::Parse(path.display().to_string(), e.to_string())])?;
+        registry.upgrade()
+    }
+
+    /// Upgrade a registry read from a file to the current version, failing if it is newer.
+    pub fn upgrade(mut self) -> Result<Self, Vec<Error>> {
+        if self.version > REGISTRY_VERSION {
+            return Err(vec![Error::RegistryVersion(self.version)]);
+        }
+        // There is only one version of the format for now, so upgrading only
+        // consists in bumping the version number.
+        self.version = REGISTRY_VERSION;
+
+        Ok(self)
+    }
+
+    /// Read a legacy `config.csv` file.
+    pub fn load_legacy(path: &Path) -> Result<Self, Vec<Error>> {
+        let file = File::open(path).map_err(|e| vec![Error::Io(e, path.display().to_string())])?;
+        Self::from_csv(file)
+    }
+
+    /// Read flakes in the format of the legacy `config.csv` file.
+    pub fn from_csv(reader: impl Read) -> Result<Self, Vec<Error>> {
+        let mut reader = csv::Reader::from_reader(reader);
+        let mut registry = Registry::default();
+        for result in reader.deserialize() {
+            let legacy: LegacyFlake = result.map_err(|e| vec![Error::Internal(Box::new(e))])?;
+            registry.flakes.push(legacy.into());
+        }
+        Ok(registry)
+    }
+
+    /// Write the flakes in the format of the legacy `config.csv` file, which
+    /// only holds their name, path and wether they are enabled.
+    pub fn to_csv(&self, writer: impl Write) -> Result<(), Vec<Error>> {
+        let mut writer = csv::Writer::from_writer(writer);
+        for named_flake in &self.flakes {
+            writer
+                .serialize(LegacyFlake {
+                    name: named_flake.name.clone(),
+                    path: named_flake.flake.path.clone(),
+                    enabled: named_flake.flake.enabled,
+                })
+                .map_err(|e| vec![Error::Internal(Box::new(e))])?;
+        }
+        writer.flush().map_err(|e| vec![Error::Io(e, "stdout".to_owned())])
+    }
+
+    /// Atomically write the registry at the given path.
+    pub fn save(&self, path: &Path) -> Result<(), Vec<Error>> {
+        let content = toml::to_string_pretty(self).map_err(|e| vec![Error::Internal(Box::new(e))])?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, content)
+            .map_err(|e| vec![Error::Io(e, tmp_path.display().to_string())])?;
+        fs::rename(&tmp_path, path).map_err(|e| vec![Error::Io(e, tmp_path.display().to_string())])
+    }
+}
+
+/// Convert the legacy `config.csv` in `config_dir` into a registry written at `registry_path`.
+/// The old file is kept with a `.bak` extension.
+pub fn migrate(config_dir: &Path, registry_path: &Path) -> Result<PathBuf, Vec<Error>> {
+    let legacy_path = config_dir.join(LEGACY_CONFIG_FILE);
+    let registry = Registry::load_legacy(&legacy_path)?;
+    registry.save(registry_path)?;
+
+    let backup_path = legacy_path.with_extension("csv.bak");
+    fs::rename(&legacy_path, &backup_path)
+        .map_err(|e| vec![Error::Io(e, legacy_path.display().to_string())])?;
+    Ok(backup_path)
+}
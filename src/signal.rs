@@ -0,0 +1,33 @@
+//! Stopping cleanly on SIGINT and SIGTERM.
+//!
+//! The handler only records that SnowPlow has been interrupted: the running nix
+//! commands notice it and are killed, and the update reports what it did so far.
+
+use std::{
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{log, Error};
+
+/// Wether SnowPlow has been interrupted.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The exit status after an interruption, as if killed by SIGINT.
+pub const EXIT_CODE: i32 = 130;
+
+/// Install the handler of SIGINT and SIGTERM. A second signal exits immediately.
+pub fn install() -> Result<(), Vec<Error>> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            process::exit(EXIT_CODE);
+        }
+        log("interrupted, stopping (interrupt again to exit immediately)", "warning");
+    })
+    .map_err(|e| vec![Error::Internal(Box::new(e))])
+}
+
+/// Wether SnowPlow has been interrupted.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,265 @@
+//! Export and import of the registry, to move the tracked flakes, the settings
+//! and the hub to another machine.
+//!
+//! The paths inside the home directory are exported relative to `~`, and
+//! imported relative to the home directory of the user importing them, so
+//! that the flakes can be moved from one user to another.
+
+use std::{
+    fs,
+    io::{self, Read},
+    path::{self, Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use directories::BaseDirs;
+
+use crate::{
+    registry::{NamedFlake, Registry},
+    is_default, warn, Error, Flake, Interface, TagFilter,
+};
+
+/// The format of an export.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    Json,
+    /// The format of the registry.
+    Toml,
+    /// The format of the registry of SnowPlow 0.1, which only holds the name,
+    /// the path and wether each flake is enabled.
+    Csv,
+}
+
+/// What to do with an imported flake whose name is already tracked, and with
+/// imported settings or hub inputs which differ from the current ones.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Conflict {
+    /// Import nothing.
+    Fail,
+    /// Keep the tracked flake, and do not import the other one.
+    Skip,
+    /// Replace the tracked flake by the imported one.
+    Overwrite,
+    /// Import the flake under a new name, such as `name-2`.
+    /// The settings and the hub inputs are kept, as with `skip`.
+    Rename,
+}
+
+impl Interface {
+    /// Print the registry in the given format, with only the tracked flakes selected by `tags`.
+    pub(crate) fn export_flakes(&self, format: Format, tags: TagFilter) -> Result<(), Vec<Error>> {
+        let home = home_dir();
+        let mut registry = Registry {
+            settings: self.settings.clone(),
+            hub: self.hub.clone(),
+            ..Registry::default()
+        };
+        let csv = matches!(format, Format::Csv);
+        if csv && (!is_default(&registry.settings) || !registry.hub.is_empty()) {
+            let msg = "the settings and the hub cannot be exported as CSV";
+            warn(msg, self.stderr_style);
+        }
+        for (name, flake) in &self.flakes {
+            if !tags.matches(flake) {
+                continue;
+            }
+            let mut flake = flake.clone();
+            if let Some(home) = &home {
+                flake.path = contract(&flake.path, home);
+            }
+            let stripped = Flake {
+                path: flake.path.clone(),
+                enabled: flake.enabled,
+                ..Flake::default()
+            };
+            if csv && flake != stripped {
+                let msg = format!("the settings of flake `{}` cannot be exported as CSV", name);
+                warn(&msg, self.stderr_style);
+            }
+            registry.flakes.push(NamedFlake::from((name.clone(), flake)));
+        }
+
+        let content = match format {
+            Format::Json => serde_json::to_string_pretty(&registry)
+                .map_err(|e| vec![Error::Internal(Box::new(e))])?,
+            Format::Toml => toml::to_string_pretty(&registry)
+                .map_err(|e| vec![Error::Internal(Box::new(e))])?,
+            Format::Csv => return registry.to_csv(io::stdout().lock()),
+        };
+        println!("{}", content.trim_end());
+        Ok(())
+    }
+
+    /// Track the flakes exported in `file`, or read from the standard input if it is `-`,
+    /// and take the settings and the hub inputs exported with them.
+    /// Nothing is imported if one of them cannot be.
+    pub(crate) fn import_flakes(
+        &mut self,
+        file: PathBuf,
+        format: Option<Format>,
+        conflict: Conflict,
+    ) -> Result<(), Vec<Error>> {
+        let source = file.display().to_string();
+        let content = if file == Path::new("-") {
+            let mut content = String::new();
+            io::stdin()
+                .read_to_string(&mut content)
+                .map_err(|e| vec![Error::Io(e, source.clone())])?;
+            content
+        } else {
+            fs::read_to_string(&file).map_err(|e| vec![Error::Io(e, source.clone())])?
+        };
+        let format = format.unwrap_or_else(|| {
+            match file.extension().and_then(|extension| extension.to_str()) {
+                Some("json") => Format::Json,
+                Some("csv") => Format::Csv,
+                _ => Format::Toml,
+            }
+        });
+        let registry = match format {
+            Format::Json => serde_json::from_str::<Registry>(&content)
+                .map_err(|e| vec![Error::Parse(source.clone(), e.to_string())])?,
+            Format::Toml => toml::from_str::<Registry>(&content)
+                .map_err(|e| vec![Error::Parse(source.clone(), e.to_string())])?,
+            Format::Csv => Registry::from_csv(content.as_bytes())?,
+        }
+        .upgrade()?;
+
+        let mut errors = Vec::new();
+        let mut settings = self.settings.clone();
+        if registry.settings != settings && !is_default(&registry.settings) {
+            if is_default(&settings) {
+                settings = registry.settings;
+            } else {
+                match conflict {
+                    Conflict::Fail => errors.push(Error::ConflictingSettings),
+                    Conflict::Skip | Conflict::Rename => {
+                        let msg = "the settings differ from the imported ones, they have been kept";
+                        warn(msg, self.stderr_style);
+                    }
+                    Conflict::Overwrite => {
+                        warn("the settings have been overwritten", self.stderr_style);
+                        settings = registry.settings;
+                    }
+                }
+            }
+        }
+
+        let mut hub = self.hub.clone();
+        for (input, url) in registry.hub {
+            match hub.get(&input) {
+                Some(old_url) if *old_url != url => match conflict {
+                    Conflict::Fail => {
+                        errors.push(Error::ConflictingHubInput(input, old_url.clone()))
+                    }
+                    Conflict::Skip | Conflict::Rename => {
+                        let msg = format!(
+                            "hub input `{}` already points to `{}`, it has been kept",
+                            input, old_url
+                        );
+                        warn(&msg, self.stderr_style);
+                    }
+                    Conflict::Overwrite => {
+                        let msg = format!("hub input `{}` has been overwritten", input);
+                        warn(&msg, self.stderr_style);
+                        hub.insert(input, url);
+                    }
+                },
+                _ => {
+                    hub.insert(input, url);
+                }
+            }
+        }
+
+        let home = home_dir();
+        let mut flakes = self.flakes.clone();
+        let mut imported = 0;
+        for named_flake in registry.flakes {
+            let (mut name, mut flake) = named_flake.into();
+            if let Some(home) = &home {
+                flake.path = expand(&flake.path, home);
+            }
+            // SnowPlow runs from anywhere, so the path must not be relative to where it was imported.
+            flake.path = path::absolute(&flake.path)
+                .map_err(|e| vec![Error::Io(e, flake.path.display().to_string())])?;
+            if flakes.contains_key(&name) {
+                match conflict {
+                    Conflict::Fail => {
+                        errors.push(Error::TrackedFlake(name));
+                        continue;
+                    }
+                    Conflict::Skip => {
+                        let msg = format!("flake `{}` is already tracked, it has been skipped", name);
+                        warn(&msg, self.stderr_style);
+                        continue;
+                    }
+                    Conflict::Overwrite => {
+                        let msg = format!("flake `{}` has been overwritten", name);
+                        warn(&msg, self.stderr_style);
+                    }
+                    Conflict::Rename => {
+                        let new_name = (2..)
+                            .map(|i| format!("{}-{}", name, i))
+                            .find(|new_name| !flakes.contains_key(new_name))
+                            .unwrap();
+                        let msg = format!(
+                            "flake `{}` is already tracked, it has been imported as `{}`",
+                            name, new_name
+                        );
+                        warn(&msg, self.stderr_style);
+                        name = new_name;
+                    }
+                }
+            }
+            if !flake.path.join("flake.nix").is_file() {
+                let msg = format!(
+                    "there is no flake at \"{}\" for flake `{}`",
+                    flake.path.display(),
+                    name
+                );
+                warn(&msg, self.stderr_style);
+            }
+            flakes.insert(name, flake);
+            imported += 1;
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        self.settings = settings;
+        self.hub = hub;
+        self.flakes = flakes;
+        match imported {
+            1 => println!("imported 1 flake"),
+            _ => println!("imported {} flakes", imported),
+        }
+        Ok(())
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    BaseDirs::new().map(|dirs| dirs.home_dir().to_owned())
+}
+
+/// Write a path inside `home` relative to `~`.
+fn contract(path: &Path, home: &Path) -> PathBuf {
+    match path.strip_prefix(home) {
+        Ok(relative) => Path::new("~").join(relative),
+        Err(_) => path.to_owned(),
+    }
+}
+
+/// Resolve a path relative to `~` or `$HOME` inside `home`.
+fn expand(path: &Path, home: &Path) -> PathBuf {
+    ["~", "$HOME"]
+        .iter()
+        .find_map(|prefix| path.strip_prefix(prefix).ok())
+        .map(|relative| {
+            if relative.as_os_str().is_empty() {
+                home.to_owned()
+            } else {
+                home.join(relative)
+            }
+        })
+        .unwrap_or_else(|| path.to_owned())
+}
@@ -0,0 +1,796 @@
+//! Updating several flakes in one run, and reporting how it went.
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::{Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use ansi_term::{Colour, Style};
+use chrono::{DateTime, Local};
+
+use crate::{
+    apply_style,
+    backend::Control,
+    backup::Backups,
+    error, git, graph,
+    hub::{self, HubInput},
+    journal,
+    lock::{self, InputChange, LockFile, LOCK_FILE},
+    registry::RetryPolicy,
+    signal, Error, Flake, Interface, Log, UpdateOptions, UpdateRequest,
+};
+
+/// The prefix of the name of the temporary work trees in which the flakes are updated on a branch.
+pub const WORKTREE_PREFIX: &str = "snow-plow-";
+
+/// The state shared by the workers updating flakes in parallel.
+struct Scheduler {
+    /// The indices of the flakes which are not being updated yet, in order.
+    pending: Vec<usize>,
+    /// Wether the update of each flake is over.
+    done: Vec<bool>,
+    /// Wether an update failed.
+    failed: bool,
+    /// The output of the update of each flake, until it is printed.
+    reports: Vec<Option<Report>>,
+}
+
+/// What every update of a run shares.
+struct Run<'a> {
+    /// The number of flakes to update.
+    nb: usize,
+    /// The number of flakes updated at the same time.
+    jobs: usize,
+    /// The inputs to update, or all of them if empty.
+    inputs: &'a [String],
+    /// The locked reference of the inputs of the hub.
+    hub_inputs: BTreeMap<String, HubInput>,
+    /// The arguments passed further to nix.
+    args: &'a [String],
+    /// Only print the changed inputs.
+    diff_only: bool,
+    /// Only print the result of the update of each flake.
+    quiet: bool,
+    /// Do not run the verification steps of the flakes.
+    no_verify: bool,
+    /// How long the update of a flake may last, unless it has its own timeout.
+    timeout: Option<Duration>,
+    retry: RetryPolicy,
+    /// Commit the lock file of every flake.
+    commit: bool,
+    /// The name of the branch on which to commit the lock files, instead of the
+    /// checked out branch, where `{name}` stands for the name of the flake.
+    branch: Option<String>,
+    /// Where the lock files are saved before being updated.
+    backups: Backups,
+    /// The identifier of the run, under which the lock files are saved.
+    run_id: String,
+}
+
+/// The outcome of the update of a flake.
+struct Report {
+    name: String,
+    log: Log,
+    status: Status,
+    /// How long the update took, if it was performed.
+    duration: Option<Duration>,
+    /// The changes of the direct inputs of the flake.
+    changes: Vec<InputChange>,
+    /// The branch on which the lock file has been committed, if any.
+    branch: Option<String>,
+    /// The arguments given to nix to update the flake, if it was updated.
+    args: Vec<String>,
+    /// How many times the update was tried again after a transient failure.
+    retries: u32,
+    errors: Vec<Error>,
+}
+
+/// What happened to a flake during a run.
+enum Status {
+    /// Its lock file changed.
+    Updated,
+    /// Its lock file did not change.
+    Unchanged,
+    Failed,
+    /// It was not updated, for the given reason.
+    Skipped(String),
+}
+
+impl Interface {
+    /// Update the given flake, or all tracked flakes, and print a summary of the run.
+    pub(crate) fn update_flakes(
+        &self,
+        name: Option<String>,
+        options: UpdateOptions,
+        args: Vec<String>,
+    ) -> Result<(), Vec<Error>> {
+        let UpdateOptions {
+            inputs,
+            fail_fast,
+            diff_only,
+            quiet,
+            no_verify,
+            commit,
+            branch,
+            no_hub,
+            timeout,
+            retries,
+            retry_delay,
+            jobs,
+            tags,
+        } = options;
+        let mut retry = self.settings.retry;
+        retry.retries = retries.unwrap_or(retry.retries);
+        retry.delay = retry_delay.unwrap_or(retry.delay);
+        let timeout = timeout.map(Duration::from_secs);
+        let selected: BTreeMap<&str, &Flake> = match name {
+            Some(name) => {
+                let Some((name, flake)) = self.flakes.get_key_value(&name) else {
+                    return Err(vec![Error::NoFlake(name)]);
+                };
+                BTreeMap::from([(name.as_str(), flake)])
+            }
+            None => self
+                .flakes
+                .iter()
+                .map(|(name, flake)| (name.as_str(), flake))
+                .collect(),
+        };
+        let selected: BTreeMap<&str, &Flake> = selected
+            .into_iter()
+            .filter(|(_, flake)| tags.matches(flake))
+            .collect();
+        let (enabled, disabled): (BTreeMap<&str, &Flake>, BTreeMap<&str, &Flake>) =
+            selected.into_iter().partition(|(_, flake)| flake.enabled);
+
+        // Fail early if nix is not supported or the flakes cannot be ordered,
+        // rather than once per flake or after the hub changed.
+        self.backend.version()?;
+        let order = graph::update_order(&enabled, self.stderr_style)?;
+        let hub_inputs = if no_hub || self.hub.is_empty() {
+            BTreeMap::new()
+        } else {
+            if !diff_only && !quiet {
+                println!("updating the hub at \"{}\"", self.hub_dir().display());
+            }
+            self.update_hub(&inputs, diff_only || quiet, timeout)?
+        };
+
+        let nb = order.len();
+        let start = Local::now();
+        let backups = Backups::new(&self.state_dir);
+        let run = Run {
+            nb,
+            jobs: jobs.get().min(nb.max(1)),
+            inputs: &inputs,
+            hub_inputs,
+            args: &args,
+            diff_only,
+            quiet,
+            no_verify,
+            timeout,
+            retry,
+            commit: commit || branch.is_some(),
+            branch: branch.map(|branch| {
+                branch
+                    .replace("{date}", &start.format("%Y-%m-%d").to_string())
+                    .replace("{time}", &start.format("%H%M%S").to_string())
+            }),
+            run_id: backups.new_run()?,
+            backups,
+        };
+
+        // Flakes are handed to the workers in order, as soon as the flakes they
+        // depend on are done. The reports are printed in order too, so that
+        // the output does not depend on the number of jobs.
+        let scheduler = Mutex::new(Scheduler {
+            pending: (0..nb).collect(),
+            done: vec![false; nb],
+            failed: false,
+            reports: (0..nb).map(|_| None).collect(),
+        });
+        let condvar = Condvar::new();
+
+        let worker = || loop {
+            let mut state = scheduler.lock().unwrap();
+            let i = loop {
+                let reason = if signal::interrupted() {
+                    Some("interrupted")
+                } else if fail_fast && state.failed {
+                    Some("cancelled")
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    for i in std::mem::take(&mut state.pending) {
+                        state.done[i] = true;
+                        state.reports[i] = Some(Report::skipped(&order[i].0, reason));
+                    }
+                    condvar.notify_all();
+                }
+                let ready = state.pending.iter().position(|&i| {
+                    order[i].1.iter().all(|dep| {
+                        let j = order.iter().position(|(name, _)| name == dep).unwrap();
+                        state.done[j]
+                    })
+                });
+                match ready {
+                    Some(position) => break state.pending.remove(position),
+                    None if state.pending.is_empty() => return,
+                    None => state = condvar.wait(state).unwrap(),
+                }
+            };
+            drop(state);
+
+            let name = &order[i].0;
+            // A panic must not leave the report missing, which would block the run forever.
+            let start = Instant::now();
+            let report = panic::catch_unwind(AssertUnwindSafe(|| {
+                self.update_one(&run, i, name, enabled[name.as_str()])
+            }))
+            .unwrap_or_else(|payload| {
+                let msg = payload
+                    .downcast_ref::<&str>()
+                    .map(|msg| msg.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "panic".to_owned());
+                Report::failed(name, Log::Direct, start, vec![Error::Internal(msg.into())])
+            });
+
+            let mut state = scheduler.lock().unwrap();
+            state.done[i] = true;
+            state.failed |= matches!(report.status, Status::Failed);
+            state.reports[i] = Some(report);
+            condvar.notify_all();
+        };
+
+        let mut reports = Vec::with_capacity(nb + disabled.len());
+        thread::scope(|scope| {
+            for _ in 0..run.jobs {
+                scope.spawn(worker);
+            }
+
+            for i in 0..nb {
+                let mut state = scheduler.lock().unwrap();
+                let mut report = loop {
+                    match state.reports[i].take() {
+                        Some(report) => break report,
+                        None => state = condvar.wait(state).unwrap(),
+                    }
+                };
+                drop(state);
+
+                report.log.flush(self.stderr_style);
+                if quiet {
+                    self.print_result(&report);
+                }
+                // We do not exit because some flake may fail to be updated while another do not.
+                for err in &report.errors {
+                    error(&err.msg(), self.stderr_style);
+                }
+                reports.push(report);
+            }
+        });
+        for name in disabled.keys() {
+            let report = Report::skipped(name, "disabled");
+            if quiet {
+                self.print_result(&report);
+            }
+            reports.push(report);
+        }
+
+        if !diff_only && !quiet {
+            self.print_summary(&reports);
+            self.print_branches(&reports);
+        }
+        // The directory of the run reserves its identifier until it is journaled.
+        let journaled = journal::append(&self.state_dir, &self.journal_run(&run, start, &reports));
+        run.backups.close_run(&run.run_id);
+        run.backups.prune(self.settings.backups)?;
+        journaled?;
+
+        if signal::interrupted() {
+            return Err(vec![Error::Interrupted]);
+        }
+        let failed = reports
+            .iter()
+            .filter(|report| matches!(report.status, Status::Failed))
+            .count();
+        if failed > 0 {
+            return Err(vec![Error::Failed(failed)]);
+        }
+        Ok(())
+    }
+
+    /// Update the `i`th flake of the run, and report how it went.
+    fn update_one(&self, run: &Run, i: usize, name: &str, flake: &Flake) -> Report {
+        let mut log = if run.jobs == 1 {
+            Log::Direct
+        } else {
+            Log::Buffered(Vec::new())
+        };
+        // Another process updating the same flake would race on its lock file.
+        let _lock = match self.lock_flake(name, &flake.path) {
+            Ok(lock) => lock,
+            Err(errors) if matches!(errors.as_slice(), [Error::FlakeBusy(_)]) => {
+                return Report {
+                    log,
+                    ..Report::skipped(name, "updated by another snow-plow")
+                }
+            }
+            Err(errors) => return Report::failed(name, log, Instant::now(), errors),
+        };
+        if !run.diff_only && !run.quiet {
+            log.info(format!(
+                "updating flake `{}` at \"{}\" {}/{}",
+                name,
+                flake.path.display(),
+                i + 1,
+                run.nb,
+            ));
+        }
+
+        let start = Instant::now();
+        match &run.branch {
+            None => self.update_at(run, name, flake, &flake.path, log, start),
+            Some(branch) => self.update_on_branch(run, name, flake, branch, log, start),
+        }
+    }
+
+    /// Update a flake in a temporary work tree, on a new branch, and commit its lock file there.
+    /// The branch is deleted if the lock file did not change.
+    fn update_on_branch(
+        &self,
+        run: &Run,
+        name: &str,
+        flake: &Flake,
+        branch: &str,
+        log: Log,
+        start: Instant,
+    ) -> Report {
+        if !git::is_repository(&flake.path) {
+            return Report {
+                log,
+                ..Report::skipped(name, "not in a git repository")
+            };
+        }
+        let branch = branch.replace("{name}", name);
+        // The branch of a previous run is never reused, it may be under review.
+        match git::has_branch(&flake.path, &branch) {
+            Ok(false) => (),
+            Ok(true) => {
+                return Report {
+                    log,
+                    ..Report::skipped(name, &format!("branch `{}` already exists", branch))
+                }
+            }
+            Err(errors) => return Report::failed(name, log, start, errors),
+        }
+        let worktree = env::temp_dir().join(format!("{}{}-{}", WORKTREE_PREFIX, run.run_id, name));
+        let prefix = match git::prefix(&flake.path)
+            .and_then(|prefix| git::add_worktree(&flake.path, &branch, &worktree).map(|()| prefix))
+        {
+            Ok(prefix) => prefix,
+            Err(errors) => return Report::failed(name, log, start, errors),
+        };
+
+        let mut report = self.update_at(run, name, flake, &worktree.join(prefix), log, start);
+
+        let mut result = git::remove_worktree(&flake.path, &worktree);
+        if let Status::Updated = report.status {
+            if !run.quiet {
+                report.log.info(format!("  committed to branch `{}`", branch));
+            }
+            report.branch = Some(branch);
+        } else {
+            result = result.and(git::delete_branch(&flake.path, &branch));
+        }
+        if let Err(errors) = result {
+            report.status = Status::Failed;
+            report.errors.extend(errors);
+        }
+        report
+    }
+
+    /// Update the flake whose directory is `path`, which is either its own
+    /// directory, or a copy of it in a temporary work tree.
+    fn update_at(
+        &self,
+        run: &Run,
+        name: &str,
+        flake: &Flake,
+        path: &Path,
+        mut log: Log,
+        start: Instant,
+    ) -> Report {
+        let lock_path = path.join(LOCK_FILE);
+
+        let inputs = if run.inputs.is_empty() {
+            Vec::new()
+        } else {
+            match self.present_inputs(path, run.inputs) {
+                Ok(inputs) if inputs.is_empty() => {
+                    let inputs: Vec<String> =
+                        run.inputs.iter().map(|input| format!("`{}`", input)).collect();
+                    let reason = format!("no input {}", inputs.join(" nor "));
+                    return Report {
+                        log,
+                        ..Report::skipped(name, &reason)
+                    };
+                }
+                Ok(inputs) => inputs,
+                Err(errors) => return Report::failed(name, log, start, errors),
+            }
+        };
+
+        let commit = (run.commit || flake.commit) && git::is_repository(path);
+        if run.commit && !commit {
+            log.warn(
+                format!(
+                    "flake `{}` is not in a git repository, its lock file will not be committed",
+                    name
+                ),
+                self.stderr_style,
+            );
+        }
+        if commit {
+            match git::is_lock_dirty(path) {
+                Ok(false) => {}
+                Ok(true) => {
+                    let errors = vec![Error::DirtyLock(name.to_owned())];
+                    return Report::failed(name, log, start, errors);
+                }
+                Err(errors) => return Report::failed(name, log, start, errors),
+            }
+        }
+
+        // The inputs which the flake overrides itself are not locked to the hub.
+        let hub_inputs: BTreeMap<String, HubInput> = run
+            .hub_inputs
+            .iter()
+            .filter(|(input, _)| !flake.overrides.contains_key(*input))
+            .map(|(name, input)| (name.clone(), input.clone()))
+            .collect();
+        let mut args = match hub::override_args(&hub_inputs, path) {
+            Ok((args, skipped)) => {
+                for input in skipped {
+                    log.warn(
+                        format!(
+                            "input `{}` does not reference the same source as the hub, it is not locked to it",
+                            input
+                        ),
+                        self.stderr_style,
+                    );
+                }
+                args
+            }
+            Err(errors) => return Report::failed(name, log, start, errors),
+        };
+        args.extend(flake.nix_args(run.args));
+
+        // There is nothing to back up when updating in a temporary work tree.
+        let backups = run.branch.is_none().then_some(&run.backups);
+        let old_lock = fs::read(&lock_path).ok();
+        if let Some(Err(errors)) =
+            backups.map(|backups| backups.save(&run.run_id, name, old_lock.as_deref()))
+        {
+            return Report::failed(name, log, start, errors);
+        }
+        let mut progress = self.progress(name, run.diff_only || run.quiet);
+        let mut control = Control {
+            progress: &mut progress,
+            start,
+            timeout: flake.timeout.map(Duration::from_secs).or(run.timeout),
+        };
+        let request = UpdateRequest {
+            inputs: &inputs,
+            args: &args,
+            retry: run.retry,
+        };
+        let (mut result, retries) = self.update_flake(path, &request, &mut log, &mut control);
+        let mut new_lock = fs::read(&lock_path).ok();
+
+        if result.is_ok() && old_lock != new_lock && !run.no_verify {
+            result = self.verify_flake(path, &flake.verify, &mut log, &mut control);
+        }
+        if result.is_err() && old_lock != new_lock {
+            // A broken, timed out or interrupted update must not stay in the working tree.
+            if let Err(errors) = lock::write(path, old_lock.as_deref()) {
+                return Report {
+                    args,
+                    retries,
+                    ..Report::failed(name, log, start, errors)
+                };
+            }
+            log.warn(
+                format!("the lock file of flake `{}` has been restored", name),
+                self.stderr_style,
+            );
+            new_lock = old_lock.clone();
+        }
+        if old_lock == new_lock {
+            if let Some(Err(errors)) = backups.map(|backups| backups.discard(&run.run_id, name)) {
+                return Report {
+                    args,
+                    retries,
+                    ..Report::failed(name, log, start, errors)
+                };
+            }
+        }
+
+        let (mut status, mut errors) = match result {
+            Ok(()) if old_lock == new_lock => (Status::Unchanged, Vec::new()),
+            Ok(()) => (Status::Updated, Vec::new()),
+            Err(errors) => (Status::Failed, errors),
+        };
+
+        let mut changes = Vec::new();
+        if let Status::Updated = status {
+            let parse = |content: &Option<Vec<u8>>| {
+                content
+                    .as_deref()
+                    .map(|content| LockFile::parse(content, &lock_path))
+                    .transpose()
+            };
+            changes = match parse(&old_lock).and_then(|old| Ok((old, parse(&new_lock)?))) {
+                Ok((old, new)) => lock::diff(old.as_ref(), new.as_ref()),
+                Err(errors) => {
+                    return Report {
+                        args,
+                        retries,
+                        ..Report::failed(name, log, start, errors)
+                    }
+                }
+            };
+            if run.diff_only && !changes.is_empty() {
+                log.info(apply_style(Style::new().bold(), name, self.stdout_style).to_string());
+            }
+            if !run.quiet {
+                for change in &changes {
+                    log.info(format!("  {}", change));
+                }
+            }
+
+            if commit {
+                if let Err(e) = git::commit_lock(path, &git::commit_message(&changes)) {
+                    status = Status::Failed;
+                    errors = e;
+                    // The lock file must neither stay staged nor changed, or the
+                    // next runs would refuse to commit it.
+                    if let Err(e) = git::unstage_lock(path)
+                        .and_then(|()| lock::write(path, old_lock.as_deref()))
+                    {
+                        errors.extend(e);
+                    } else {
+                        log.warn(
+                            format!("the lock file of flake `{}` has been restored", name),
+                            self.stderr_style,
+                        );
+                        changes.clear();
+                        if let Some(Err(e)) =
+                            backups.map(|backups| backups.discard(&run.run_id, name))
+                        {
+                            errors.extend(e);
+                        }
+                    }
+                }
+            }
+        }
+
+        Report {
+            name: name.to_owned(),
+            log,
+            status,
+            duration: Some(start.elapsed()),
+            changes,
+            branch: None,
+            args,
+            retries,
+            errors,
+        }
+    }
+
+    /// Describe a run for the journal.
+    fn journal_run(&self, run: &Run, start: DateTime<Local>, reports: &[Report]) -> journal::Run {
+        let flakes = reports
+            .iter()
+            .map(|report| {
+                let (status, reason) = match &report.status {
+                    Status::Updated => ("updated", None),
+                    Status::Unchanged => ("unchanged", None),
+                    Status::Failed => ("failed", None),
+                    Status::Skipped(reason) => ("skipped", Some(reason.clone())),
+                };
+                journal::FlakeRun {
+                    name: report.name.clone(),
+                    path: self.flakes[&report.name].path.clone(),
+                    status: status.to_owned(),
+                    reason,
+                    duration: report.duration.map(|duration| duration.as_secs_f64()),
+                    args: report.args.clone(),
+                    retries: report.retries,
+                    errors: report.errors.iter().map(Error::msg).collect(),
+                    changes: report.changes.iter().map(journal::Change::from).collect(),
+                }
+            })
+            .collect();
+        journal::Run {
+            id: run.run_id.clone(),
+            time: start.timestamp(),
+            inputs: run.inputs.to_vec(),
+            args: run.args.to_vec(),
+            flakes,
+        }
+    }
+
+    /// Return the inputs among `inputs` which the flake at `path` has.
+    fn present_inputs(&self, path: &Path, inputs: &[String]) -> Result<Vec<String>, Vec<Error>> {
+        let Some(lock) = LockFile::read(path)? else {
+            return Ok(Vec::new());
+        };
+        let names: Vec<&str> = lock.root_inputs().map(|(name, _)| name).collect();
+        Ok(inputs
+            .iter()
+            .filter(|input| names.contains(&input.as_str()))
+            .cloned()
+            .collect())
+    }
+
+    /// Print a table with the status of every flake of the run.
+    fn print_summary(&self, reports: &[Report]) {
+        let mut counts = [0; 4];
+        let rows: Vec<(&str, String, Colour, String)> = reports
+            .iter()
+            .map(|report| {
+                let (status, colour) = report.status.describe();
+                counts[match report.status {
+                    Status::Updated => 0,
+                    Status::Unchanged => 1,
+                    Status::Failed => 2,
+                    Status::Skipped(_) => 3,
+                }] += 1;
+                let mut duration = report
+                    .duration
+                    .map(|duration| format!("{:.1}s", duration.as_secs_f64()))
+                    .unwrap_or_else(|| "-".to_owned());
+                match report.retries {
+                    0 => {}
+                    1 => duration.push_str(" (1 retry)"),
+                    nb => duration.push_str(&format!(" ({} retries)", nb)),
+                }
+                (report.name.as_str(), status, colour, duration)
+            })
+            .collect();
+
+        let name_width = rows.iter().map(|row| row.0.len()).chain([5]).max().unwrap();
+        let status_width = rows.iter().map(|row| row.1.len()).chain([6]).max().unwrap();
+
+        println!();
+        println!(
+            "{}",
+            apply_style(
+                Style::new().bold(),
+                format!(
+                    "{:name_width$}  {:status_width$}  duration",
+                    "flake", "status"
+                ),
+                self.stdout_style,
+            ),
+        );
+        for (name, status, colour, duration) in rows {
+            println!(
+                "{:name_width$}  {}  {}",
+                name,
+                apply_style(
+                    Style::new().fg(colour),
+                    format!("{:status_width$}", status),
+                    self.stdout_style,
+                ),
+                duration,
+            );
+        }
+        println!(
+            "{} updated, {} unchanged, {} failed, {} skipped",
+            counts[0], counts[1], counts[2], counts[3],
+        );
+    }
+}
+
+impl Interface {
+    /// Print the result of the update of a flake on one line, for `--quiet`.
+    fn print_result(&self, report: &Report) {
+        let (status, colour) = report.status.describe();
+        let mut line = format!(
+            "{}: {}",
+            report.name,
+            apply_style(Style::new().fg(colour), status, self.stdout_style)
+        );
+        match report.changes.len() {
+            0 => {}
+            1 => line.push_str(", 1 input changed"),
+            nb => line.push_str(&format!(", {} inputs changed", nb)),
+        }
+        match report.retries {
+            0 => {}
+            1 => line.push_str(", after 1 retry"),
+            nb => line.push_str(&format!(", after {} retries", nb)),
+        }
+        if let Some(branch) = &report.branch {
+            line.push_str(&format!(", on branch `{}`", branch));
+        }
+        println!("{}", line);
+    }
+
+    /// Print the branches created for review.
+    fn print_branches(&self, reports: &[Report]) {
+        let branches: Vec<(&str, &str)> = reports
+            .iter()
+            .filter_map(|report| Some((report.name.as_str(), report.branch.as_deref()?)))
+            .collect();
+        if branches.is_empty() {
+            return;
+        }
+        println!();
+        println!(
+            "{}",
+            apply_style(Style::new().bold(), "branches to review", self.stdout_style)
+        );
+        for (name, branch) in branches {
+            println!(
+                "{}: {} in \"{}\"",
+                name,
+                branch,
+                self.flakes[name].path.display()
+            );
+        }
+    }
+}
+
+impl Status {
+    /// Return the name of the status, and its colour.
+    fn describe(&self) -> (String, Colour) {
+        match self {
+            Status::Updated => ("updated".to_owned(), Colour::Green),
+            Status::Unchanged => ("unchanged".to_owned(), Colour::White),
+            Status::Failed => ("failed".to_owned(), Colour::Red),
+            Status::Skipped(reason) => (format!("skipped ({})", reason), Colour::Yellow),
+        }
+    }
+}
+
+impl Report {
+    /// The report of a flake which failed to be updated.
+    fn failed(name: &str, log: Log, start: Instant, errors: Vec<Error>) -> Self {
+        Report {
+            name: name.to_owned(),
+            log,
+            status: Status::Failed,
+            duration: Some(start.elapsed()),
+            changes: Vec::new(),
+            branch: None,
+            args: Vec::new(),
+            retries: 0,
+            errors,
+        }
+    }
+
+    /// The report of a flake which was not updated.
+    fn skipped(name: &str, reason: &str) -> Self {
+        Report {
+            name: name.to_owned(),
+            log: Log::Direct,
+            status: Status::Skipped(reason.to_owned()),
+            duration: None,
+            changes: Vec::new(),
+            branch: None,
+            args: Vec::new(),
+            retries: 0,
+            errors: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,1462 @@
+//! Tests of the command line, running SnowPlow with a fake nix replaying a script.
+//! The fake nix is only built in debug builds, so these tests are only run by them.
+#![cfg(debug_assertions)]
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    thread,
+    time::Duration,
+};
+
+use fs4::fs_std::FileExt;
+use tempfile::TempDir;
+
+/// A temporary configuration, state and set of flakes.
+struct Env {
+    dir: TempDir,
+}
+
+impl Env {
+    /// Create an environment where nix behaves as described by `script`.
+    fn new(script: &str) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("calls.log");
+        let script = format!("log = {:?}\n{}", log.display().to_string(), script);
+        fs::write(dir.path().join("nix.toml"), script).unwrap();
+        Env { dir }
+    }
+
+    /// Create the directory of a flake, with the given lock file if any.
+    fn flake(&self, name: &str, lock: Option<&str>) -> PathBuf {
+        let path = self.dir.path().join(name);
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+        if let Some(lock) = lock {
+            fs::write(path.join("flake.lock"), lock).unwrap();
+        }
+        path
+    }
+
+    /// Return the command running SnowPlow with the given arguments.
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_snow-plow"));
+        command
+            .args(args)
+            .env("SNOW_PLOW_CONFIG", self.dir.path().join("config"))
+            .env("SNOW_PLOW_STATE", self.dir.path().join("state"))
+            .env("SNOW_PLOW_FAKE_NIX", self.dir.path().join("nix.toml"));
+        command
+    }
+
+    /// Run SnowPlow with the given arguments.
+    fn run(&self, args: &[&str]) -> Output {
+        self.command(args).output().unwrap()
+    }
+
+    /// Track a flake, which must succeed.
+    fn add(&self, name: &str, path: &Path) {
+        let output = self.run(&["add", name, path.to_str().unwrap()]);
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+
+    /// Return the operations run by the fake nix.
+    fn calls(&self) -> Vec<String> {
+        fs::read_to_string(self.dir.path().join("calls.log"))
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+}
+
+/// Return a lock file with a `nixpkgs` input locked to the given revision.
+fn lock(rev: &str, last_modified: i64) -> String {
+    branch_lock(rev, last_modified, None)
+}
+
+/// Return a lock file with a `nixpkgs` input following the given branch, locked to the given revision.
+fn branch_lock(rev: &str, last_modified: i64, branch: Option<&str>) -> String {
+    let reference = branch
+        .map(|branch| format!(r#", "ref": "{}""#, branch))
+        .unwrap_or_default();
+    format!(
+        r#"{{"nodes": {{"root": {{"inputs": {{"nixpkgs": "nixpkgs"}}}}, "nixpkgs": {{"locked": {{"type": "github", "owner": "NixOS", "repo": "nixpkgs", "rev": "{}", "lastModified": {}}}, "original": {{"type": "github", "owner": "NixOS", "repo": "nixpkgs"{}}}}}}}, "root": "root", "version": 7}}"#,
+        rev, last_modified, reference
+    )
+}
+
+/// Run git in `dir`, which must succeed, and return its standard output.
+fn git(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    stdout(&output)
+}
+
+/// Make `dir` a git repository, with its content committed.
+fn git_init(dir: &Path) {
+    git(dir, &["init", "--quiet"]);
+    git(dir, &["config", "user.name", "SnowPlow"]);
+    git(dir, &["config", "user.email", "snow-plow@example.com"]);
+    git(dir, &["add", "."]);
+    git(dir, &["commit", "--quiet", "-m", "init"]);
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn add_and_list() {
+    let env = Env::new("");
+    let path = env.flake("system", None);
+    env.add("system", &path);
+
+    let output = env.run(&["list"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains(&format!("system {} enabled", path.display())));
+    assert_eq!(env.calls(), ["metadata system"]);
+}
+
+#[test]
+fn legacy_config_is_migrated() {
+    let env = Env::new("");
+    let system = env.flake("system", None);
+    let web = env.flake("web", None);
+    let config = env.dir.path().join("config");
+    fs::create_dir(&config).unwrap();
+    let csv = format!(
+        "name,path,enabled\nsystem,{},true\nweb,{},false\n",
+        system.display(),
+        web.display()
+    );
+    fs::write(config.join("config.csv"), &csv).unwrap();
+
+    let output = env.run(&["list"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("the registry has been migrated to"));
+    assert_eq!(
+        stdout(&output),
+        format!("system {} enabled\nweb {} disabled\n", system.display(), web.display())
+    );
+    assert!(!config.join("config.csv").exists());
+    assert_eq!(fs::read_to_string(config.join("config.csv.bak")).unwrap(), csv);
+    let registry = fs::read_to_string(config.join("registry.toml")).unwrap();
+    assert!(registry.starts_with("version = 1\n"));
+
+    // The registry is only migrated once.
+    let output = env.run(&["list"]);
+    assert!(!stderr(&output).contains("migrated"));
+
+    // A registry written by a newer SnowPlow is not read, nor overwritten.
+    let newer = registry.replacen("version = 1", "version = 2", 1);
+    fs::write(config.join("registry.toml"), &newer).unwrap();
+    let output = env.run(&["disable", "system"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains(
+        "the registry uses version 2 of the format, which is not supported by this version of SnowPlow"
+    ));
+    assert_eq!(fs::read_to_string(config.join("registry.toml")).unwrap(), newer);
+}
+
+#[test]
+fn info_reads_metadata_on_demand() {
+    let env = Env::new(
+        r#"
+        [flakes.system.metadata]
+        stdout = '{"description": "My system", "lastModified": 1700000000}'
+        "#,
+    );
+    let path = env.flake("system", None);
+    env.add("system", &path);
+
+    let output = env.run(&["info", "system"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!stdout(&output).contains("description"));
+    assert_eq!(env.calls(), ["metadata system"]);
+
+    let output = env.run(&["info", "system", "--metadata"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("description: My system"));
+    assert!(stdout(&output).contains("last modified: 2023-11-14"));
+    assert_eq!(env.calls(), ["metadata system", "metadata system"]);
+}
+
+#[test]
+fn add_invalid_flake() {
+    let env = Env::new(
+        r#"
+        [flakes.broken.metadata]
+        success = false
+        stderr = """
+warning: Git tree is dirty
+error: syntax error, unexpected end of file
+       at /tmp/broken/flake.nix:3:1:
+"""
+        "#,
+    );
+    let path = env.flake("broken", None);
+
+    let output = env.run(&["add", "broken", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("warning: nix: Git tree is dirty"));
+    assert!(stderr.contains("evaluation error: syntax error, unexpected end of file"));
+    assert!(stderr.contains("at /tmp/broken/flake.nix:3:1"));
+
+    let output = env.run(&["list"]);
+    assert!(stdout(&output).trim().is_empty());
+}
+
+#[test]
+fn update_prints_changes_and_summary() {
+    let env = Env::new(&format!(
+        "[flakes.system.update]\nlock = '{}'\n",
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    env.add("system", &path);
+
+    let output = env.run(&["update"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = stdout(&output);
+    assert!(stdout.contains("nixpkgs: aaaaaaa (2020-09-13) -> bbbbbbb (2023-11-14)"));
+    assert!(stdout.contains("1 updated, 0 unchanged, 0 failed, 0 skipped"));
+    assert_eq!(
+        fs::read_to_string(path.join("flake.lock")).unwrap(),
+        lock("bbbbbbbbbb", 1700000000)
+    );
+    assert_eq!(env.calls(), ["metadata system", "update system"]);
+}
+
+#[test]
+fn diff_only_prints_the_changes() {
+    let env = Env::new(&format!(
+        "[flakes.system.update]\nlock = '{}'\n",
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    let web = env.flake("web", Some(&lock("aaaaaaaaaa", 1600000000)));
+    env.add("system", &path);
+    env.add("web", &web);
+
+    // Only the flakes whose inputs changed are printed, without any summary.
+    let output = env.run(&["update", "--diff-only"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "system\n  nixpkgs: aaaaaaa (2020-09-13) -> bbbbbbb (2023-11-14), 1157 days\n"
+    );
+}
+
+/// Return a lock file with a `lib` input at the given path.
+fn path_lock(path: &Path) -> String {
+    format!(
+        r#"{{"nodes": {{"root": {{"inputs": {{"lib": "lib"}}}}, "lib": {{"locked": {{"type": "path", "path": {:?}, "lastModified": 1600000000}}}}}}, "root": "root", "version": 7}}"#,
+        path.display().to_string()
+    )
+}
+
+#[test]
+fn flakes_are_updated_after_their_inputs() {
+    let env = Env::new("");
+    let lib = env.flake("lib", None);
+    let app = env.flake("app", Some(&path_lock(&lib)));
+    let x = env.flake("x", None);
+    let y = env.flake("y", None);
+    for (name, path) in [("app", &app), ("lib", &lib), ("x", &x), ("y", &y)] {
+        env.add(name, path);
+    }
+    let registry = env.dir.path().join("config/registry.toml");
+    let content = fs::read_to_string(&registry).unwrap();
+    fs::write(&registry, content.replace("name = \"y\"\n", "name = \"y\"\npriority = 10\n")).unwrap();
+
+    // `y` comes first by priority, and `app` right after `lib`, before `x` by name.
+    let output = env.run(&["update"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let updates: Vec<_> = env.calls().into_iter().filter(|call| call.starts_with("update")).collect();
+    assert_eq!(updates, ["update y", "update lib", "update app", "update x"]);
+
+    // A cycle stops the run before anything is updated, the hub included.
+    fs::write(lib.join("flake.lock"), path_lock(&app)).unwrap();
+    assert!(env.run(&["hub", "add", "nixpkgs", "github:NixOS/nixpkgs"]).status.success());
+    let before = env.calls().len();
+    let output = env.run(&["update"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output)
+        .contains("tracked flakes depend on each other in a cycle: `app` -> `lib` -> `app`"));
+    assert_eq!(env.calls().len(), before);
+}
+
+#[test]
+fn broken_lock_does_not_stop_the_run() {
+    let env = Env::new(&format!(
+        "[flakes.system.update]\nlock = '{}'\n",
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let broken = env.flake("broken", None);
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    env.add("broken", &broken);
+    env.add("system", &path);
+    fs::write(broken.join("flake.lock"), "{").unwrap();
+
+    let output = env.run(&["update"]);
+    assert!(stderr(&output)
+        .contains("the inputs of flake `broken` are unknown, it may be updated before them"));
+    assert!(stdout(&output).contains("1 updated"));
+    assert!(env.calls().contains(&"update system".to_owned()));
+}
+
+#[test]
+fn failed_update() {
+    let env = Env::new(
+        r#"
+        [flakes.system.update]
+        success = false
+        stderr = """
+error: unable to download 'https://github.com/NixOS/nixpkgs/archive/abc.tar.gz': HTTP error 404
+"""
+        "#,
+    );
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    let other = env.flake("other", None);
+    env.add("system", &path);
+    env.add("other", &other);
+
+    let output = env.run(&["update"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stderr(&output).contains("HTTP error 404"));
+    assert!(stderr(&output).contains("1 flake failed to be updated"));
+    assert!(stdout(&output).contains("0 updated, 1 unchanged, 1 failed, 0 skipped"));
+    // A missing archive is not worth trying again.
+    assert_eq!(
+        env.calls().iter().filter(|call| *call == "update system").count(),
+        1
+    );
+}
+
+#[test]
+fn fail_fast_cancels_the_remaining_flakes() {
+    let env = Env::new(
+        r#"
+        [flakes.app.update]
+        success = false
+        stderr = "error: unable to download 'https://example.org/a.tar.gz': HTTP error 404"
+        "#,
+    );
+    for name in ["app", "system", "web"] {
+        let path = env.flake(name, None);
+        env.add(name, &path);
+    }
+
+    let output = env.run(&["update", "--fail-fast"]);
+    assert_eq!(output.status.code(), Some(3));
+    let stdout = stdout(&output);
+    assert!(stdout.contains("system  skipped (cancelled)"), "{}", stdout);
+    assert!(stdout.contains("web     skipped (cancelled)"), "{}", stdout);
+    assert!(stdout.contains("0 updated, 0 unchanged, 1 failed, 2 skipped"));
+    let updates: Vec<_> = env.calls().into_iter().filter(|call| call.starts_with("update")).collect();
+    assert_eq!(updates, ["update app"]);
+}
+
+#[test]
+fn transient_failures_are_retried() {
+    let env = Env::new(
+        r#"
+        [flakes.system.update]
+        success = false
+        stderr = """
+error:
+       … while updating the lock file of flake 'path:/tmp/system?lastModified=1700000000&narHash=sha256-abc'
+
+       … while updating the flake input 'nixpkgs'
+
+       … while fetching the input 'github:NixOS/nixpkgs'
+
+       error: unable to download 'https://api.github.com/repos/NixOS/nixpkgs/commits/HEAD': HTTP error 429
+"""
+        "#,
+    );
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    env.add("system", &path);
+
+    let output = env.run(&["update", "--retries", "2", "--retry-delay", "0.01"]);
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("retrying in 0.0s (1/2)"));
+    assert!(stderr.contains("retrying in 0.0s (2/2)"));
+    assert!(stdout(&output).contains("(2 retries)"));
+    assert_eq!(
+        env.calls().iter().filter(|call| *call == "update system").count(),
+        3
+    );
+}
+
+#[test]
+fn update_skips_flakes_without_the_inputs() {
+    let env = Env::new("");
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    let other = env.flake("other", None);
+    env.add("system", &path);
+    env.add("other", &other);
+
+    let output = env.run(&["update", "--input", "nixpkgs"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("0 updated, 1 unchanged, 0 failed, 1 skipped"));
+    assert!(env.calls().contains(&"update system nixpkgs".to_owned()));
+    assert!(!env.calls().iter().any(|call| call.starts_with("update other")));
+}
+
+#[test]
+fn failed_verification_restores_the_lock() {
+    let env = Env::new(&format!(
+        r#"
+        [flakes.system.update]
+        lock = '{}'
+
+        [flakes.system.verify]
+        success = false
+        stderr = "error: attribute 'hello' missing"
+        "#,
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    env.add("system", &path);
+    let registry = env.dir.path().join("config/registry.toml");
+    let content = fs::read_to_string(&registry).unwrap();
+    fs::write(&registry, format!("{}verify = [\"check\"]\n", content)).unwrap();
+
+    let output = env.run(&["update"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stderr(&output).contains("attribute 'hello' missing"));
+    assert_eq!(
+        fs::read_to_string(path.join("flake.lock")).unwrap(),
+        lock("aaaaaaaaaa", 1600000000)
+    );
+}
+
+#[test]
+fn rollback_restores_the_lock() {
+    let env = Env::new(&format!(
+        "[flakes.system.update]\nlock = '{}'\n",
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    env.add("system", &path);
+
+    assert!(env.run(&["update"]).status.success());
+    let output = env.run(&["rollback", "system"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        fs::read_to_string(path.join("flake.lock")).unwrap(),
+        lock("aaaaaaaaaa", 1600000000)
+    );
+}
+
+#[test]
+fn only_the_last_backups_are_kept() {
+    let env = Env::new(&format!(
+        "[flakes.system.update]\nlock = '{}'\n",
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let path = env.flake("system", None);
+    env.add("system", &path);
+    let registry = env.dir.path().join("config/registry.toml");
+    let content = fs::read_to_string(&registry).unwrap();
+    fs::write(&registry, content.replacen("\n", "\n[settings]\nbackups = 2\n", 1)).unwrap();
+
+    for _ in 0..4 {
+        fs::write(path.join("flake.lock"), lock("aaaaaaaaaa", 1600000000)).unwrap();
+        assert!(env.run(&["update"]).status.success());
+    }
+    let journal = fs::read_to_string(env.dir.path().join("state/history.jsonl")).unwrap();
+    let ids: Vec<String> = journal
+        .lines()
+        .map(|line| {
+            let run: serde_json::Value = serde_json::from_str(line).unwrap();
+            run["id"].as_str().unwrap().to_owned()
+        })
+        .collect();
+    let mut runs: Vec<String> = fs::read_dir(env.dir.path().join("state/backups"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    runs.sort();
+    assert_eq!(runs, ids[2..]);
+}
+
+#[test]
+fn backups_of_flakes_with_any_name() {
+    let new_lock = lock("bbbbbbbbbb", 1700000000);
+    let env = Env::new(&format!(
+        "[flakes.system.update]\nlock = '{}'\n\n[flakes.web.update]\nlock = '{}'\n",
+        new_lock, new_lock
+    ));
+    let system = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    let web = env.flake("web", Some(&lock("aaaaaaaaaa", 1600000000)));
+    env.add("work/c", &system);
+    env.add("../../c2", &web);
+
+    let output = env.run(&["update"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("2 updated"));
+    assert!(!env.dir.path().join("c2.lock").exists());
+    for (name, path) in [("work/c", &system), ("../../c2", &web)] {
+        let output = env.run(&["rollback", name]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(
+            fs::read_to_string(path.join("flake.lock")).unwrap(),
+            lock("aaaaaaaaaa", 1600000000)
+        );
+    }
+}
+
+#[test]
+fn runs_have_distinct_identifiers() {
+    let env = Env::new("");
+    let path = env.flake("system", None);
+    env.add("system", &path);
+
+    // The runs are quick enough to start within the same second.
+    for _ in 0..3 {
+        assert!(env.run(&["update"]).status.success());
+    }
+    let journal = fs::read_to_string(env.dir.path().join("state/history.jsonl")).unwrap();
+    let mut ids: Vec<_> = journal
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["id"].clone())
+        .collect();
+    ids.dedup();
+    assert_eq!(ids.len(), 3);
+    // The directories of runs without backups are removed once journaled.
+    assert_eq!(fs::read_dir(env.dir.path().join("state/backups")).unwrap().count(), 0);
+}
+
+#[test]
+fn history_shows_the_journal() {
+    let env = Env::new(&format!(
+        r#"
+        [flakes.system.update]
+        lock = '{}'
+
+        [flakes.web.update]
+        success = false
+        stderr = "error: unable to download 'https://example.org/a.tar.gz': HTTP error 404"
+        "#,
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let system = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    let web = env.flake("web", None);
+    env.add("system", &system);
+    env.add("web", &web);
+
+    assert_eq!(env.run(&["update", "--", "--refresh"]).status.code(), Some(3));
+    assert_eq!(env.run(&["update", "web"]).status.code(), Some(3));
+
+    // Each run is a line of JSON.
+    let path = env.dir.path().join("state/history.jsonl");
+    let journal = fs::read_to_string(&path).unwrap();
+    let runs: Vec<serde_json::Value> = journal
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(runs.len(), 2);
+    assert_eq!(runs[0]["args"], serde_json::json!(["--refresh"]));
+    let flakes = &runs[0]["flakes"];
+    assert_eq!(flakes[0]["name"], "system");
+    assert_eq!(flakes[0]["status"], "updated");
+    assert_eq!(flakes[0]["path"], system.to_str().unwrap());
+    assert_eq!(flakes[0]["changes"][0]["input"], "nixpkgs");
+    assert_eq!(flakes[0]["changes"][0]["old"]["rev"], "aaaaaaaaaa");
+    assert_eq!(flakes[0]["changes"][0]["new"]["rev"], "bbbbbbbbbb");
+    assert_eq!(flakes[1]["name"], "web");
+    assert_eq!(flakes[1]["status"], "failed");
+    assert!(flakes[1]["errors"][0].as_str().unwrap().contains("HTTP error 404"));
+
+    let output = env.run(&["history"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = self::stdout(&output);
+    assert_eq!(stdout.matches("run ").count(), 2);
+    assert!(stdout.contains(", nix arguments --refresh\n"));
+    assert!(stdout.contains(
+        "  system updated\n    nixpkgs: aaaaaaa (2020-09-13) -> bbbbbbb (2023-11-14)\n"
+    ));
+    assert!(stdout.contains("  web failed\n    "));
+
+    let output = env.run(&["history", "system"]);
+    let stdout = self::stdout(&output);
+    assert_eq!(stdout.matches("run ").count(), 1);
+    assert!(!stdout.contains("web"));
+
+    // Move the first run back in time.
+    let time = format!("\"time\":{}", runs[0]["time"]);
+    fs::write(&path, journal.replacen(&time, "\"time\":1600000000", 1)).unwrap();
+    let output = env.run(&["history", "--until", "2021-01-01"]);
+    let stdout = self::stdout(&output);
+    assert_eq!(stdout.matches("run ").count(), 1);
+    assert!(stdout.contains("system updated"));
+    let output = env.run(&["history", "--since", "2021-01-01"]);
+    let stdout = self::stdout(&output);
+    assert_eq!(stdout.matches("run ").count(), 1);
+    assert!(!stdout.contains("system"));
+    let output = env.run(&["history", "system", "--since", "2021-01-01"]);
+    assert!(self::stdout(&output).is_empty());
+}
+
+#[test]
+fn nix_settings_are_applied() {
+    let env = Env::new("");
+    let calls = env.dir.path().join("nix-calls.log");
+    let nix = env.dir.path().join("my-nix");
+    fs::write(
+        &nix,
+        format!(
+            "#!/bin/sh\necho \"$NIX_CONFIG|$*\" >> '{}'\necho 'nix (Nix) 2.24.1'\n",
+            calls.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&nix, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+    let path = env.flake("system", None);
+
+    fs::create_dir(env.dir.path().join("config")).unwrap();
+    fs::write(
+        env.dir.path().join("config/registry.toml"),
+        "version = 1\n\n[settings.nix]\noptions = [\"--extra-experimental-features\", \"nix-command flakes\"]\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_snow-plow"))
+        .args(["add", "system", path.to_str().unwrap()])
+        .args(["--nix", nix.to_str().unwrap()])
+        .args(["--nix-option", "--option", "--nix-option", "sandbox", "--nix-option", "false"])
+        .args(["--nix-env", "NIX_CONFIG=allow-dirty = true"])
+        .env("SNOW_PLOW_CONFIG", env.dir.path().join("config"))
+        .env("SNOW_PLOW_STATE", env.dir.path().join("state"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let options = "--extra-experimental-features nix-command flakes --option sandbox false";
+    assert_eq!(
+        fs::read_to_string(&calls).unwrap(),
+        format!(
+            "allow-dirty = true|{} --version\nallow-dirty = true|{} --log-format internal-json flake metadata --json {}\n",
+            options,
+            options,
+            path.display()
+        )
+    );
+}
+
+#[test]
+fn json_errors_are_parsed() {
+    let env = Env::new(
+        r#"
+        [flakes.system.metadata]
+        success = false
+        stderr = '''
+@nix {"action":"start","id":1,"level":4,"text":"evaluating flake","type":0}
+@nix {"action":"msg","level":3,"msg":"evaluating the outputs"}
+@nix {"action":"msg","level":1,"msg":"\u001b[35;1mwarning:\u001b[0m Git tree is dirty"}
+@nix {"action":"msg","level":0,"msg":"\u001b[31;1merror:\u001b[0m undefined variable 'hello'","raw_msg":"undefined variable '\u001b[35;1mhello\u001b[0m'","file":"/tmp/system/flake.nix","line":4,"column":12,"trace":[{"raw_msg":"while evaluating the attribute 'packages'","file":"/tmp/system/flake.nix","line":3,"column":5}]}
+'''
+        "#,
+    );
+    let path = env.flake("system", None);
+
+    let output = env.run(&["add", "system", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("warning: nix: Git tree is dirty"));
+    assert!(stderr.contains(
+        "evaluation error: undefined variable 'hello'\n  at /tmp/system/flake.nix:4:12\n  \
+         … while evaluating the attribute 'packages'\n    at /tmp/system/flake.nix:3:5"
+    ));
+    assert!(stderr.contains("[system] evaluating the outputs"));
+    assert!(!stderr.contains("[system] evaluating flake"));
+
+    let output = env.run(&["add", "system", path.to_str().unwrap(), "--verbose"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("[system] evaluating flake"));
+}
+
+#[test]
+fn text_errors_are_classified() {
+    let env = Env::new(
+        r#"
+        [flakes.system.update]
+        success = false
+        stderr = """
+error:
+       … while updating the lock file of flake 'path:/tmp/system?lastModified=1700000000&narHash=sha256-abc'
+
+       … while updating the flake input 'nixpkgs'
+
+       … while fetching the input 'github:NixOS/nixpkgs'
+
+       error: unable to download 'https://api.github.com/repos/NixOS/nixpkgs/commits/HEAD': HTTP error 503
+"""
+
+        [flakes.other.update]
+        success = false
+        stderr = "error: cannot write modified lock file of flake 'path:/tmp/other' (use '--no-write-lock-file' to ignore)"
+        "#,
+    );
+    let path = env.flake("system", None);
+    let other = env.flake("other", None);
+    env.add("system", &path);
+    env.add("other", &other);
+
+    let output = env.run(&["update", "--retries", "0"]);
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("fetch error: unable to download"));
+    assert!(stderr.contains("… while updating the flake input 'nixpkgs'"));
+    assert!(stderr.contains("… while fetching the input 'github:NixOS/nixpkgs'"));
+    assert!(stderr.contains("lock file error: cannot write modified lock file"));
+}
+
+#[test]
+fn update_streams_progress() {
+    let env = Env::new(&format!(
+        r#"
+        [flakes.system.update]
+        lock = '{}'
+        stderr = '''
+@nix {{"action":"start","id":1,"level":3,"text":"fetching 'github:NixOS/nixpkgs'","type":0}}
+@nix {{"action":"stop","id":1}}
+'''
+        "#,
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    let other = env.flake("other", None);
+    env.add("system", &path);
+    env.add("other", &other);
+
+    let output = env.run(&["update"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("[system] fetching 'github:NixOS/nixpkgs'"));
+
+    fs::write(path.join("flake.lock"), lock("aaaaaaaaaa", 1600000000)).unwrap();
+    let output = env.run(&["update", "--quiet"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).is_empty());
+    assert_eq!(
+        stdout(&output),
+        "other: unchanged\nsystem: updated, 1 input changed\n"
+    );
+}
+
+#[test]
+fn timed_out_update_restores_the_lock() {
+    let env = Env::new(&format!(
+        "[flakes.system.update]\nlock = '{}'\nsleep = 10\n",
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    env.add("system", &path);
+
+    let output = env.run(&["update", "--timeout", "1"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stderr(&output).contains("timed out after 1s"));
+    assert!(stdout(&output).contains("0 updated, 0 unchanged, 1 failed, 0 skipped"));
+    assert_eq!(
+        fs::read_to_string(path.join("flake.lock")).unwrap(),
+        lock("aaaaaaaaaa", 1600000000)
+    );
+}
+
+#[test]
+fn interrupted_update_stops_cleanly() {
+    let env = Env::new(&format!(
+        "[flakes.system.update]\nlock = '{}'\nsleep = 10\n",
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    let web = env.flake("web", None);
+    env.add("system", &path);
+    env.add("web", &web);
+
+    let child = env
+        .command(&["update", "--jobs", "1"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Wait for nix to be running on the first flake.
+    while !env.calls().iter().any(|call| call == "update system") {
+        thread::sleep(Duration::from_millis(20));
+    }
+    let status = Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(130));
+    assert!(stderr(&output).contains("interrupted"));
+    assert!(stdout(&output).contains("0 updated, 0 unchanged, 1 failed, 1 skipped"));
+    assert_eq!(
+        fs::read_to_string(path.join("flake.lock")).unwrap(),
+        lock("aaaaaaaaaa", 1600000000)
+    );
+    assert!(!env.calls().iter().any(|call| call == "update web"));
+}
+
+#[test]
+fn validation_levels() {
+    let env = Env::new(
+        r#"
+        [flakes.system.check]
+        success = false
+        stderr = "error: a 'aarch64-darwin' with features {} is required to build"
+        "#,
+    );
+    let path = env.flake("system", None);
+    let output = env.run(&["add", "system", path.to_str().unwrap(), "--no-check"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(env.calls().is_empty());
+
+    let missing = env.dir.path().join("missing");
+    fs::create_dir(&missing).unwrap();
+    let output = env.run(&["add", "missing", missing.to_str().unwrap(), "--level", "syntax"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("it has no `flake.nix`"));
+
+    let output = env.run(&["check"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("system: valid"));
+    assert_eq!(env.calls(), ["metadata system"]);
+
+    let output = env.run(&["check", "system", "--level", "show"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stdout(&output).contains("system: invalid"));
+    assert!(stderr(&output).contains("1 flake is not valid"));
+    assert_eq!(env.calls(), ["metadata system", "check system"]);
+}
+
+#[test]
+fn tags_select_flakes() {
+    let env = Env::new("");
+    for name in ["laptop", "server", "web"] {
+        let path = env.flake(name, None);
+        env.add(name, &path);
+    }
+    let output = env.run(&["tag", "add", "work", "laptop", "server"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(env.run(&["tag", "add", "prod", "server", "web"]).status.success());
+
+    let output = env.run(&["tag", "list"]);
+    assert_eq!(stdout(&output), "prod server web\nwork laptop server\n");
+
+    let output = env.run(&["list", "--tag", "work", "--exclude-tag", "prod"]);
+    let stdout = stdout(&output);
+    assert!(stdout.contains("laptop "));
+    assert!(!stdout.contains("server "));
+    assert!(!stdout.contains("web "));
+
+    let output = env.run(&["disable", "--tag", "prod"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = env.run(&["list", "--disabled"]);
+    assert_eq!(self::stdout(&output).lines().count(), 2);
+
+    let output = env.run(&["update", "--tag", "work"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let calls = env.calls();
+    assert!(calls.contains(&"update laptop".to_owned()));
+    assert!(!calls.contains(&"update server".to_owned()));
+    assert!(!calls.contains(&"update web".to_owned()));
+
+    assert!(env.run(&["tag", "remove", "work", "laptop"]).status.success());
+    let output = env.run(&["tag", "list"]);
+    assert_eq!(self::stdout(&output), "prod server web\nwork server\n");
+}
+
+#[test]
+fn hub_inputs_are_shared() {
+    let env = Env::new(&format!(
+        "[flakes.hub.update]\nlock = '{}'\n",
+        lock("hhhhhhhhhh", 1700000000)
+    ));
+    let system = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    let web = env.flake("web", Some(&lock("aaaaaaaaaa", 1600000000)));
+    let other = env.flake("other", None);
+    let stable = Some("nixos-24.05");
+    let server = env.flake("server", Some(&branch_lock("aaaaaaaaaa", 1600000000, stable)));
+    env.add("system", &system);
+    env.add("web", &web);
+    env.add("other", &other);
+    env.add("server", &server);
+    let pinned = "github:NixOS/nixpkgs/nixos-24.05";
+    let output = env.run(&["set", "web", "--override-input", "nixpkgs", pinned]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let unstable = "github:NixOS/nixpkgs/nixos-unstable";
+    assert!(env.run(&["hub", "add", "nixpkgs", unstable]).status.success());
+    let output = env.run(&["hub", "add", "nixpkgs", "github:NixOS/nixpkgs"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let msg = format!("hub input `nixpkgs` was pointing to `{}`", unstable);
+    assert!(stderr(&output).contains(&msg));
+    let home_manager = "github:nix-community/home-manager";
+    assert!(env.run(&["hub", "add", "home-manager", home_manager]).status.success());
+    let output = env.run(&["hub", "list"]);
+    assert_eq!(
+        stdout(&output),
+        format!(
+            "home-manager {} not locked\nnixpkgs github:NixOS/nixpkgs not locked\n",
+            home_manager
+        )
+    );
+    assert!(env.run(&["hub", "remove", "home-manager"]).status.success());
+    let output = env.run(&["hub", "remove", "home-manager"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("hub input `home-manager` does not exists"));
+
+    // The hub is updated first, and the inputs of the flakes are locked to it,
+    // unless a flake overrides them itself or follows another branch.
+    let output = env.run(&["update"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains(
+        "input `nixpkgs` does not reference the same source as the hub, it is not locked to it"
+    ));
+    let locked = "github:NixOS/nixpkgs/hhhhhhhhhh";
+    let updates: Vec<_> = env.calls().into_iter().filter(|call| call.starts_with("update")).collect();
+    assert_eq!(
+        updates,
+        [
+            "update hub".to_owned(),
+            "update other".to_owned(),
+            "update server".to_owned(),
+            format!("update system --override-input nixpkgs {}", locked),
+            format!("update web --override-input nixpkgs {}", pinned),
+        ]
+    );
+    // The journal records the arguments each flake was updated with.
+    let journal = fs::read_to_string(env.dir.path().join("state/history.jsonl")).unwrap();
+    let run: serde_json::Value = serde_json::from_str(journal.lines().last().unwrap()).unwrap();
+    let args: Vec<_> = run["flakes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|flake| (flake["name"].as_str().unwrap(), flake["args"].clone()))
+        .collect();
+    assert_eq!(
+        args,
+        [
+            ("other", serde_json::Value::Null),
+            ("server", serde_json::Value::Null),
+            ("system", serde_json::json!(["--override-input", "nixpkgs", locked])),
+            ("web", serde_json::json!(["--override-input", "nixpkgs", pinned])),
+        ]
+    );
+    let output = env.run(&["hub", "list"]);
+    assert_eq!(stdout(&output), format!("nixpkgs github:NixOS/nixpkgs {}\n", locked));
+
+    let before = env.calls().len();
+    let output = env.run(&["update", "--no-hub"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        env.calls()[before..],
+        [
+            "update other".to_owned(),
+            "update server".to_owned(),
+            "update system".to_owned(),
+            format!("update web --override-input nixpkgs {}", pinned),
+        ]
+    );
+}
+
+#[test]
+fn flake_nix_arguments_are_merged() {
+    let env = Env::new("");
+    let path = env.flake("system", None);
+    let other = env.flake("other", None);
+    env.add("system", &path);
+    env.add("other", &other);
+
+    let output = env.run(&[
+        "set",
+        "system",
+        "--arg",
+        "--impure",
+        "--override-input",
+        "secrets",
+        "path:/run/secrets",
+    ]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = env.run(&["info", "system"]);
+    assert!(stdout(&output).contains("nix arguments: --impure"));
+    assert!(stdout(&output).contains("input `secrets` overridden by `path:/run/secrets`"));
+
+    let output = env.run(&["update", "--", "--refresh"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let calls = env.calls();
+    assert!(calls.contains(
+        &"update system --override-input secrets path:/run/secrets --impure --refresh".to_owned()
+    ));
+    assert!(calls.contains(&"update other --refresh".to_owned()));
+
+    let output = env.run(&["set", "system", "--clear-args", "--remove-override", "secrets"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let registry = fs::read_to_string(env.dir.path().join("config/registry.toml")).unwrap();
+    assert!(!registry.contains("--impure"));
+    assert!(!registry.contains("secrets"));
+}
+
+#[test]
+fn concurrent_runs_do_not_clash() {
+    let env = Env::new(&format!(
+        "[flakes.system.update]\nlock = '{}'\nsleep = 10\n",
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    env.add("system", &path);
+    let registry = env.dir.path().join("config/registry.toml");
+    let content = format!("# my flakes\n{}", fs::read_to_string(&registry).unwrap());
+    fs::write(&registry, &content).unwrap();
+
+    // Read-only commands leave the registry as it is.
+    assert!(env.run(&["list"]).status.success());
+    assert_eq!(fs::read_to_string(&registry).unwrap(), content);
+
+    let mut child = env
+        .command(&["update"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    while !env.calls().iter().any(|call| call == "update system") {
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    // The flake is being updated by the first run.
+    let output = env.run(&["update", "system"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("skipped (updated by another snow-plow)"));
+    // The registry is not held by an update.
+    let web = env.flake("web", None);
+    env.add("web", &web);
+
+    Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    child.wait().unwrap();
+    assert!(stdout(&env.run(&["list"])).contains("web "));
+}
+
+#[test]
+fn concurrent_runs_update_the_hub_in_turn() {
+    let env = Env::new(&format!(
+        "[flakes.hub.update]\nlock = '{}'\nsleep = 1\n",
+        lock("hhhhhhhhhh", 1700000000)
+    ));
+    let path = env.flake("system", None);
+    env.add("system", &path);
+    assert!(env.run(&["hub", "add", "nixpkgs", "github:NixOS/nixpkgs"]).status.success());
+
+    let child = env
+        .command(&["update"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    while !env.calls().iter().any(|call| call == "update hub") {
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    let output = env.run(&["update"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("flake `hub` is being updated by another snow-plow"));
+    let output = env.run(&["update", "--wait"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("waiting for another snow-plow to finish updating flake `hub`"));
+    assert!(child.wait_with_output().unwrap().status.success());
+    assert_eq!(env.calls().iter().filter(|call| *call == "update hub").count(), 2);
+}
+
+#[test]
+fn entries_sharing_a_path_are_updated_in_turn() {
+    let env = Env::new(&format!(
+        "[flakes.system.update]\nlock = '{}'\nsleep = 0.2\n",
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    env.add("laptop", &path);
+    env.add("desktop", &path);
+
+    let output = env.run(&["update", "--jobs", "2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = stdout(&output);
+    assert!(!stdout.contains("updated by another snow-plow"), "{}", stdout);
+    assert!(stdout.contains("1 updated, 1 unchanged, 0 failed, 0 skipped"));
+    let updates: Vec<_> = env.calls().into_iter().filter(|call| call.starts_with("update")).collect();
+    assert_eq!(updates, ["update system", "update system"]);
+}
+
+#[test]
+fn locked_registry_is_not_changed() {
+    let env = Env::new("");
+    let path = env.flake("system", None);
+    fs::create_dir(env.dir.path().join("config")).unwrap();
+    let lock = fs::File::create(env.dir.path().join("config/registry.lock")).unwrap();
+    lock.lock_exclusive().unwrap();
+
+    let output = env.run(&["add", "system", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("another snow-plow is changing the registry"));
+
+    let child = env
+        .command(&["add", "system", path.to_str().unwrap(), "--wait"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(200));
+    drop(lock);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("waiting for another snow-plow"));
+    assert!(stdout(&env.run(&["list"])).contains("system "));
+}
+
+#[test]
+fn export_and_import() {
+    let env = Env::new("");
+    let path = env.flake("system", None);
+    env.add("system", &path);
+    assert!(env.run(&["tag", "add", "work", "system"]).status.success());
+    let registry = env.dir.path().join("config/registry.toml");
+    let content = fs::read_to_string(&registry).unwrap();
+    fs::write(&registry, content.replacen("\n", "\n[settings]\nbackups = 3\n", 1)).unwrap();
+    assert!(env.run(&["hub", "add", "nixpkgs", "github:NixOS/nixpkgs"]).status.success());
+
+    let output = env
+        .command(&["export", "--format", "json"])
+        .env("HOME", env.dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let export = stdout(&output);
+    assert!(export.contains(r#""path": "~/system""#));
+    assert!(export.contains(r#""work""#));
+    assert!(export.contains(r#""backups": 3"#));
+    assert!(export.contains(r#""nixpkgs": "github:NixOS/nixpkgs""#));
+    let file = env.dir.path().join("flakes.json");
+    fs::write(&file, &export).unwrap();
+
+    // Another user on another machine.
+    let other = Env::new("");
+    let home = other.dir.path();
+    other.flake("system", None);
+    let import = |strategy: &str| {
+        other
+            .command(&["import", file.to_str().unwrap(), "--on-conflict", strategy])
+            .env("HOME", home)
+            .output()
+            .unwrap()
+    };
+    let output = import("fail");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "imported 1 flake\n");
+    let output = other.run(&["info", "system"]);
+    assert!(stdout(&output).contains(&format!("system {} enabled", home.join("system").display())));
+    assert!(stdout(&output).contains("tags: work"));
+    let registry = fs::read_to_string(home.join("config/registry.toml")).unwrap();
+    assert!(registry.contains("backups = 3"));
+    assert!(stdout(&other.run(&["hub", "list"])).contains("nixpkgs github:NixOS/nixpkgs"));
+
+    let output = import("fail");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("flake `system` is already tracked"));
+    let unstable = "github:NixOS/nixpkgs/nixos-unstable";
+    assert!(other.run(&["hub", "add", "nixpkgs", unstable]).status.success());
+    let output = import("fail");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output)
+        .contains("hub input `nixpkgs` already points to `github:NixOS/nixpkgs/nixos-unstable`"));
+    assert!(import("skip").status.success());
+    assert!(stdout(&other.run(&["hub", "list"])).contains("nixos-unstable"));
+    let output = import("rename");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("it has been imported as `system-2`"));
+    assert_eq!(stdout(&other.run(&["list"])).lines().count(), 2);
+
+    let output = other.run(&["export", "--format", "csv"]);
+    assert!(stderr(&output).contains("the settings of flake `system` cannot be exported as CSV"));
+    assert!(stdout(&output).starts_with("name,path,enabled\n"));
+    assert!(stderr(&output).contains("the settings and the hub cannot be exported as CSV"));
+
+    // Relative paths are resolved from where the flakes are imported.
+    let third = Env::new("");
+    third.flake("web", None);
+    let file = third.dir.path().join("flakes.toml");
+    let content = "version = 1\n\n[[flake]]\nname = \"web\"\npath = \"web\"\nenabled = true\n";
+    fs::write(&file, content).unwrap();
+    let output = third
+        .command(&["import", "flakes.toml"])
+        .current_dir(third.dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let web = third.dir.path().join("web");
+    assert!(stdout(&third.run(&["list"])).contains(&format!("web {} enabled", web.display())));
+}
+
+#[test]
+fn commit_only_the_lock_file() {
+    let env = Env::new(&format!(
+        "[flakes.system.update]\nlock = '{}'\n",
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    git_init(&path);
+    env.add("system", &path);
+
+    // The changes of the lock file which are not ours are not committed.
+    fs::write(path.join("flake.lock"), lock("cccccccccc", 1650000000)).unwrap();
+    let output = env.run(&["update", "--commit"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stderr(&output)
+        .contains("the lock file of flake `system` has uncommitted changes, refusing to commit it"));
+    assert!(!env.calls().contains(&"update system".to_owned()));
+    assert_eq!(git(&path, &["rev-list", "--count", "HEAD"]).trim(), "1");
+
+    git(&path, &["checkout", "--quiet", "--", "flake.lock"]);
+    fs::write(path.join("flake.nix"), "{ outputs = _: { x = 1; }; }").unwrap();
+    fs::write(path.join("notes.txt"), "todo").unwrap();
+    let output = env.run(&["update", "--commit"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let commit = git(&path, &["show", "--name-only", "--format=%s", "HEAD"]);
+    assert_eq!(commit, "flake.lock: update nixpkgs\n\nflake.lock\n");
+    let status = git(&path, &["status", "--porcelain"]);
+    assert!(status.contains(" M flake.nix"));
+    assert!(status.contains("?? notes.txt"));
+
+    // Staged changes of the lock file are not ours either.
+    fs::write(path.join("flake.lock"), lock("cccccccccc", 1650000000)).unwrap();
+    git(&path, &["add", "flake.lock"]);
+    let output = env.run(&["update", "--commit"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stderr(&output).contains("has uncommitted changes, refusing to commit it"));
+    git(&path, &["reset", "--quiet", "--", "flake.lock"]);
+    git(&path, &["checkout", "--quiet", "--", "flake.lock"]);
+
+    // A failed commit leaves neither a staged nor a changed lock file.
+    git(&path, &["reset", "--quiet", "--hard", "HEAD~"]);
+    git(&path, &["config", "--unset", "user.name"]);
+    git(&path, &["config", "--unset", "user.email"]);
+    git(&path, &["config", "user.useConfigOnly", "true"]);
+    let output = env
+        .command(&["update", "--commit"])
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env_remove("EMAIL")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stderr(&output).contains("the lock file of flake `system` has been restored"));
+    assert_eq!(
+        fs::read_to_string(path.join("flake.lock")).unwrap(),
+        lock("aaaaaaaaaa", 1600000000)
+    );
+    assert_eq!(git(&path, &["status", "--porcelain", "--", "flake.lock"]), "");
+    assert_eq!(git(&path, &["rev-list", "--count", "HEAD"]).trim(), "1");
+}
+
+#[test]
+fn update_on_a_branch() {
+    let env = Env::new(&format!(
+        "[flakes.system.update]\nlock = '{}'\n",
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    git_init(&path);
+    env.add("system", &path);
+
+    let output = env.run(&["update", "--branch", "update/{name}"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("committed to branch `update/system`"));
+    assert!(stdout(&output).contains("1 updated, 0 unchanged, 0 failed, 0 skipped"));
+    assert!(env.calls().contains(&"update system".to_owned()));
+    // The lock file is only changed on the branch, and the work tree is removed.
+    let branch_lock = git(&path, &["show", "update/system:flake.lock"]);
+    assert_eq!(branch_lock, lock("bbbbbbbbbb", 1700000000));
+    let lock_file = fs::read_to_string(path.join("flake.lock")).unwrap();
+    assert_eq!(lock_file, lock("aaaaaaaaaa", 1600000000));
+    assert_eq!(git(&path, &["worktree", "list"]).lines().count(), 1);
+    assert!(git(&path, &["status", "--porcelain"]).is_empty());
+
+    // The branch of the previous run is left as it is.
+    let output = env.run(&["update", "--branch", "update/{name}"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("skipped (branch `update/system` already exists)"));
+    assert_eq!(env.calls().iter().filter(|call| *call == "update system").count(), 1);
+
+    // Unless the name holds the time of the run.
+    let output = env.run(&["update", "--branch", "update/{name}-{time}"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("committed to branch `update/system-"));
+    assert_eq!(git(&path, &["branch", "--list", "update/*"]).lines().count(), 2);
+}
+
+#[test]
+fn rename_and_move() {
+    let env = Env::new(&format!(
+        "[flakes.system.update]\nlock = '{}'\n",
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let path = env.flake("system", Some(&lock("aaaaaaaaaa", 1600000000)));
+    env.add("system", &path);
+    assert!(env.run(&["update"]).status.success());
+    assert!(env.run(&["disable", "system"]).status.success());
+
+    let output = env.run(&["rename", "system", "laptop"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&env.run(&["list"])).contains(&format!("laptop {} disabled", path.display())));
+    // The backups follow the flake.
+    let output = env.run(&["rollback", "laptop"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let empty = env.dir.path().join("empty");
+    fs::create_dir(&empty).unwrap();
+    let output = env.run(&["move", "laptop", empty.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("it has no `flake.nix`"));
+
+    let moved = env.flake("moved", None);
+    let output = env.run(&["move", "laptop", moved.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&env.run(&["list"])).contains(&format!("laptop {} disabled", moved.display())));
+    assert!(env.calls().contains(&"metadata moved".to_owned()));
+}
+
+#[test]
+fn edit_reopens_until_valid() {
+    let env = Env::new("");
+    let path = env.flake("system", None);
+    env.add("system", &path);
+    let other = env.flake("other", None);
+
+    // The first edit duplicates the flake, with a typo and a wrong path,
+    // the second one renames the copy and fixes it, with a relative path.
+    let editor = env.dir.path().join("editor");
+    let count = env.dir.path().join("count");
+    fs::write(
+        &editor,
+        format!(
+            r#"#!/bin/sh
+echo >> '{count}'
+if [ "$(wc -l < '{count}')" -eq 1 ]; then
+    printf '\n[[flake]]\nname = "system"\npath = "/tmp"\nenabled = true\nverfy = ["check"]\n' >> "$1"
+else
+    awk '/name = "system"/ {{ n++; if (n == 2) sub("system", "other") }} !/verfy/ {{ sub("\"/tmp\"", "\"other\""); print }}' "$1" > "$1.new"
+    mv "$1.new" "$1"
+fi
+"#,
+            count = count.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&editor, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+    let output = env
+        .command(&["edit"])
+        .env("VISUAL", &editor)
+        .current_dir(env.dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("line 9: flake `system` is present several times"));
+    assert!(stderr.contains("line 10: `/tmp` is not a flake, it has no `flake.nix`"));
+    assert!(stderr.contains("line 12: unknown field `verfy` in flake `system`"));
+    assert!(stderr.contains("the registry is not valid, it is opened again"));
+    let list = stdout(&env.run(&["list"]));
+    assert!(list.contains("system "));
+    assert!(list.contains(&format!("other {} enabled", other.display())));
+
+    // A failing editor leaves the registry as it was.
+    let output = env.command(&["edit"]).env("VISUAL", "false").output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(self::stderr(&output).contains("the registry has not been changed"));
+    assert_eq!(stdout(&env.run(&["list"])), list);
+}
+
+#[test]
+fn invalid_retry_delays_are_rejected() {
+    let env = Env::new(
+        r#"
+        [flakes.system.update]
+        success = false
+        stderr = "error: unable to download 'https://example.org/a.tar.gz': HTTP error 503"
+        "#,
+    );
+    let path = env.flake("system", None);
+    env.add("system", &path);
+
+    // A wrong argument is a usage error, distinct from a failed update.
+    let output = env.run(&["update", "--retry-delay=-1"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("is not a positive number of seconds"));
+    assert!(!env.calls().iter().any(|call| call.starts_with("update")));
+
+    // A huge delay is capped, and still stopped by the timeout.
+    let output = env.run(&["update", "--retry-delay", "1e300", "--retries", "100", "--timeout", "1"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stderr(&output).contains("timed out after 1s"));
+
+    let registry = env.dir.path().join("config/registry.toml");
+    let content = fs::read_to_string(&registry).unwrap();
+    let content = content.replacen("version = 1\n", "version = 1\n\n[settings.retry]\ndelay = -1.0\n", 1);
+    fs::write(&registry, content).unwrap();
+    let output = env.run(&["list"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("`-1` is not a positive number of seconds"));
+}
+
+#[test]
+fn panicking_update_does_not_block_the_run() {
+    let env = Env::new(&format!(
+        "[flakes.system.update]\npanic = true\n\n[flakes.web.update]\nlock = '{}'\n",
+        lock("bbbbbbbbbb", 1700000000)
+    ));
+    let path = env.flake("system", None);
+    let web = env.flake("web", Some(&lock("aaaaaaaaaa", 1600000000)));
+    env.add("system", &path);
+    env.add("web", &web);
+
+    for jobs in ["1", "2"] {
+        let output = env.run(&["update", "--jobs", jobs]);
+        assert_eq!(output.status.code(), Some(3));
+        assert!(stderr(&output).contains("internal: the fake nix panicked while running `update system`"));
+        assert!(stdout(&output).contains("1 failed"));
+        fs::write(web.join("flake.lock"), lock("aaaaaaaaaa", 1600000000)).unwrap();
+    }
+}
+
+#[test]
+fn missing_nix_is_an_error() {
+    let env = Env::new("");
+    let path = env.flake("system", None);
+    env.add("system", &path);
+
+    let output = env
+        .command(&["update", "--nix", "/nonexistent/nix"])
+        .env_remove("SNOW_PLOW_FAKE_NIX")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("/nonexistent/nix"));
+}